@@ -1,12 +1,14 @@
 use std::str;
 use std::ptr;
 use std::error::Error as ErrorTrait;
-use std::io::{Result,Error,Read};
+use std::io::{Result,Error,ErrorKind,Read};
 use std::ffi::{CStr,CString};
 
-use libc::{c_void,ssize_t};
+use libc::{c_void,c_char,c_int,ssize_t};
 use libarchive3_sys::ffi;
 
+use locale;
+
 
 /* This is a safe, limited and opinionated wrapper around the libarchive C bindings.
  * I initially used the libarchive crate, but it has several issues. Some of which are not fixable
@@ -23,16 +25,20 @@ use libarchive3_sys::ffi;
 
 pub struct Archive<'a> {
     a: *mut ffi::Struct_archive,
-    rd: &'a mut Read,
+    rd: Option<&'a mut Read>, // None when reading from disk
     buf: Vec<u8>,
     err: Option<Error>,
     eof: bool,
+    // For disk archives: the root directory to strip from path names
+    root: Option<String>,
 }
 
 
 pub struct ArchiveEntry<'a> {
     a: Box<Archive<'a>>,
     e: *mut ffi::Struct_archive_entry,
+    // Decoded path name, see locale::decode_path()
+    path: Option<String>,
 }
 
 pub struct RawEntry<'a>(Box<Archive<'a>>);
@@ -43,7 +49,7 @@ pub enum FileType {
     File,
     Directory,
     Link(String),
-    Other, // Also includes Link(<non-utf8-path>)
+    Other,
 }
 
 // Top-level formats, as in ARCHIVE_FORMAT_*
@@ -51,14 +57,48 @@ pub enum FileType {
 pub enum Format {
     Tar,
     Ar,
+    Zip,
     Other, // Ultra lazyness
 }
 
 
+/* The interface of an archive entry as used by walk() and the archread module. Implemented by
+ * ArchiveEntry and by readers for formats that libarchive doesn't support. */
+pub trait Entry: Read + Sized {
+    fn next(self) -> Result<Option<Self>>;
+    fn path(&self) -> Option<&str>;
+    fn size(&self) -> usize;
+    fn mtime(&self) -> i64;
+    fn filetype(&self) -> FileType;
+    fn nlink(&self) -> u32 { 1 }
+    fn format(&self) -> Format { Format::Other }
+}
+
+
+// The archive_read_disk API is not (fully) exposed by libarchive3-sys.
+extern "C" {
+    fn archive_read_disk_new() -> *mut ffi::Struct_archive;
+    fn archive_read_disk_set_symlink_physical(a: *mut ffi::Struct_archive) -> c_int;
+    fn archive_read_disk_open(a: *mut ffi::Struct_archive, path: *const c_char) -> c_int;
+    fn archive_read_disk_can_descend(a: *mut ffi::Struct_archive) -> c_int;
+    fn archive_read_disk_descend(a: *mut ffi::Struct_archive) -> c_int;
+}
+
+
+// Returns the bytes of a C string, or None if it's NULL.
+unsafe fn cbytes<'b>(ptr: *const c_char) -> Option<&'b [u8]> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_bytes())
+    }
+}
+
+
 unsafe extern "C" fn archive_read_cb(_: *mut ffi::Struct_archive, data: *mut c_void, buf: *mut *const c_void) -> ssize_t {
     let arch: &mut Archive = &mut *(data as *mut Archive);
     *buf = arch.buf.as_mut_ptr() as *mut c_void;
-    match arch.rd.read(&mut arch.buf[..]) {
+    match arch.rd.as_mut().unwrap().read(&mut arch.buf[..]) {
         Ok(s) => s as ssize_t,
         Err(e) => {
             let desc = CString::new(e.description()).unwrap();
@@ -76,7 +116,7 @@ impl<'a> Archive<'a> {
         let bufsize = 64*1024;
         let mut buf = Vec::with_capacity(bufsize);
         unsafe { buf.set_len(bufsize) };
-        let mut ret = Box::new(Archive { a: a, rd: rd, buf: buf, err: None, eof: false });
+        let mut ret = Box::new(Archive { a: a, rd: Some(rd), buf: buf, err: None, eof: false, root: None });
 
         let aptr: *mut c_void = &mut *ret as *mut Archive as *mut c_void;
         let r = unsafe { ffi::archive_read_open(a, aptr, None, Some(archive_read_cb), None) };
@@ -104,14 +144,18 @@ impl<'a> Archive<'a> {
     fn entry(self: Box<Self>) -> Result<Option<ArchiveEntry<'a>>> {
         let mut ent = ArchiveEntry {
             a: self,
-            e: ptr::null_mut()
+            e: ptr::null_mut(),
+            path: None,
         };
         ent.a.eof = false;
         let res = unsafe { ffi::archive_read_next_header(ent.a.a, &mut ent.e) };
         match res {
             ffi::ARCHIVE_EOF => Ok(None),
             ffi::ARCHIVE_FATAL => Err(ent.a.error()),
-            _ => Ok(Some(ent))
+            _ => {
+                ent.path = ent.rawpath().map(|p| locale::decode_path(p, p));
+                Ok(Some(ent))
+            }
         }
     }
 
@@ -141,6 +185,28 @@ impl<'a> Archive<'a> {
         try!(Self::new(rd, a)).entry()
     }
 
+    /* Walk through a directory on the local filesystem. Symlinks are not followed. Path names are
+     * relative to the given root directory, so that absolute symlinks can be resolved within the
+     * root. This makes it possible to read a chroot or a mounted image in the same way as a
+     * package archive.
+     */
+    pub fn open_dir(root: &str, dir: &str) -> Result<Option<ArchiveEntry<'static>>> {
+        let cdir = CString::new(dir).map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid directory name"))?;
+        let a = unsafe {
+            let a = archive_read_disk_new();
+            archive_read_disk_set_symlink_physical(a);
+            a
+        };
+        let mut a = Box::new(Archive {
+            a: a, rd: None, buf: Vec::new(), err: None, eof: false,
+            root: Some(root.trim_right_matches('/').to_string())
+        });
+        if unsafe { archive_read_disk_open(a.a, cdir.as_ptr()) } != ffi::ARCHIVE_OK {
+            return Err(a.error());
+        }
+        a.entry()
+    }
+
     pub fn open_raw(rd: &mut Read) -> Result<RawEntry> {
         let a  = unsafe {
             let a = ffi::archive_read_new();
@@ -175,19 +241,25 @@ impl<'a> Drop for Archive<'a> {
 
 impl<'a> ArchiveEntry<'a> {
     pub fn next(self) -> Result<Option<ArchiveEntry<'a>>> {
+        if self.a.root.is_some() && unsafe { archive_read_disk_can_descend(self.a.a) } != 0 {
+            unsafe { archive_read_disk_descend(self.a.a) };
+        }
         self.a.entry()
     }
 
-    // Returns None in NULL (when does that even happen?) or on invalid UTF-8.
+    // The path name as stored in the archive, without any decoding or normalization.
+    fn rawpath(&self) -> Option<&[u8]> {
+        unsafe { cbytes(ffi::archive_entry_pathname(self.e)) }
+    }
+
+    // Returns None in NULL (when does that even happen?). Path names that aren't valid UTF-8 are
+    // decoded based on the locale in the path, see locale::decode_path().
     pub fn path(&self) -> Option<&str> {
-        let c_str: &CStr = unsafe {
-            let ptr = ffi::archive_entry_pathname(self.e);
-            if ptr.is_null() {
-                return None;
-            }
-            CStr::from_ptr(ptr)
-        };
-        str::from_utf8(c_str.to_bytes()).ok()
+        self.path.as_ref().map(String::as_str)
+            .map(|s| match self.a.root {
+                Some(ref r) if s.starts_with(r as &str) && s[r.len()..].starts_with('/') => &s[r.len()..],
+                _ => s,
+            })
             // Perform some simple opinionated normalization. Full normalization might be better,
             // but also slower and more complex. This solution covers the most important cases.
             .map(|s| s.trim_left_matches('/').trim_left_matches("./").trim_right_matches('/'))
@@ -205,6 +277,7 @@ impl<'a> ArchiveEntry<'a> {
         // might be different.
         match unsafe { ffi::archive_format(self.a.a) } >> 16 {
             0x3 => Format::Tar,
+            0x5 => Format::Zip,
             0x7 => Format::Ar,
             _   => Format::Other,
         }
@@ -216,29 +289,20 @@ impl<'a> ArchiveEntry<'a> {
         unsafe { ffi::archive_entry_mtime(self.e) }
     }
 
+    // Relative symlinks usually don't have a locale in their target, so the path of the link itself
+    // is used to guess the encoding.
     fn symlink(&self) -> Option<String> {
-        let c_str: &CStr = unsafe {
-            let ptr = ffi::archive_entry_symlink(self.e);
-            if ptr.is_null() {
-                return None;
-            }
-            CStr::from_ptr(ptr)
-        };
-        str::from_utf8(c_str.to_bytes()).map(str::to_string).ok()
+        unsafe { cbytes(ffi::archive_entry_symlink(self.e)) }
+            .map(|l| locale::decode_path(l, self.rawpath().unwrap_or(b"")))
     }
 
     fn hardlink(&self) -> Option<String> {
-        let c_str: &CStr = unsafe {
-            let ptr = ffi::archive_entry_hardlink(self.e);
-            if ptr.is_null() {
-                return None;
-            }
-            CStr::from_ptr(ptr)
-        };
         // Hard links have the same name as an earlier pathname(), and those typically don't have a
         // preceding slash. Add this slash here so that the same resolution logic can be used for
         // both hardlinks and symlinks. I really don't care about the difference between these two.
-        str::from_utf8(c_str.to_bytes()).map(|p| format!("/{}", p)).ok()
+        // The name is decoded in the same way as that pathname(), so that they still match.
+        unsafe { cbytes(ffi::archive_entry_hardlink(self.e)) }
+            .map(|l| format!("/{}", locale::decode_path(l, l)))
     }
 
     pub fn filetype(&self) -> FileType {
@@ -260,6 +324,17 @@ impl<'a> ArchiveEntry<'a> {
 }
 
 
+impl<'a> Entry for ArchiveEntry<'a> {
+    fn next(self) -> Result<Option<ArchiveEntry<'a>>> { ArchiveEntry::next(self) }
+    fn path(&self) -> Option<&str> { ArchiveEntry::path(self) }
+    fn size(&self) -> usize { ArchiveEntry::size(self) }
+    fn mtime(&self) -> i64 { ArchiveEntry::mtime(self) }
+    fn filetype(&self) -> FileType { ArchiveEntry::filetype(self) }
+    fn nlink(&self) -> u32 { ArchiveEntry::nlink(self) }
+    fn format(&self) -> Format { ArchiveEntry::format(self) }
+}
+
+
 impl<'a> Read for ArchiveEntry<'a> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         self.a.read(buf)
@@ -277,8 +352,8 @@ impl<'a> Read for RawEntry<'a> {
 // We can't provide an Iterator object for ArchiveEntries because Rust doesn't support streaming
 // iterators. Let's instead provide a walk function for convenience.
 // cb should return Ok(true) to continue, Ok(false) to break
-pub fn walk<F>(ent: Option<ArchiveEntry>, mut cb: F) -> Result<()>
-    where F: FnMut(&mut ArchiveEntry) -> Result<bool>
+pub fn walk<E,F>(ent: Option<E>, mut cb: F) -> Result<()>
+    where E: Entry, F: FnMut(&mut E) -> Result<bool>
 {
     let mut ent = ent;
     while let Some(mut e) = ent {
@@ -351,11 +426,31 @@ mod tests {
         t(&mut ent, Some("simple/fifo"), 0, FileType::Other, "");
 
         ent = ent.next().unwrap().unwrap();
-        t(&mut ent, None, 0, FileType::File, "");
+        // ISO-8859-1, there's no locale to go by
+        t(&mut ent, Some("Héllö.txt"), 0, FileType::File, "");
 
         assert!(ent.next().unwrap().is_none());
     }
 
+    #[test]
+    fn nonutf8() {
+        let mut f = File::open("tests/nonutf8.tar.xz").unwrap();
+        let mut ents = Vec::new();
+        walk(Archive::open_archive(&mut f).unwrap(), |e| {
+            if e.filetype() != FileType::Directory {
+                ents.push((e.path().unwrap().to_string(), e.filetype()));
+            }
+            Ok(true)
+        }).unwrap();
+        ents.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(ents, vec![
+            ("man/ja_JP.eucJP/man1/link.1".to_string(), FileType::Link("テスト.1".to_string())),
+            ("man/ja_JP.eucJP/man1/テスト.1".to_string(), FileType::File),
+            ("man/ru/man1/hardlink.1".to_string(), FileType::Link("/man/ru/man1/тест.1".to_string())),
+            ("man/ru/man1/тест.1".to_string(), FileType::File),
+        ]);
+    }
+
     #[test]
     fn raw() {
         let mut f = File::open("tests/rawtest.gz.xz.bzip2").unwrap();
@@ -365,6 +460,16 @@ mod tests {
         assert_eq!(&c, "File contents!\n");
     }
 
+    #[test]
+    fn raw_zstd() {
+        // Requires libarchive >= 3.3.3
+        let mut f = File::open("tests/rawtest.zst").unwrap();
+        let mut r = Archive::open_raw(&mut f).unwrap();
+        let mut c = String::new();
+        r.read_to_string(&mut c).unwrap();
+        assert_eq!(&c, "File contents!\n");
+    }
+
     #[test]
     fn raw_passthrough() {
         let mut r = std::io::Cursor::new(&b"This is an uncompressed text file"[..]);
@@ -1,7 +1,11 @@
-use std::io::Result;
+use std::io::{Result,copy};
 use std::collections::HashMap;
+use std::fs::{File,create_dir,remove_file,remove_dir_all};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::{env,process};
 
-use archive::{walk,ArchiveEntry,FileType};
+use archive::{walk,Entry,FileType};
 
 /* I had hoped that reading man pages from an archive would just be a simple:
  *
@@ -17,8 +21,10 @@ use archive::{walk,ArchiveEntry,FileType};
  * 5. Read the entire damn archive again if one of the links resolved to a file that was not
  *    recognized as a man page in step (2). Luckily, this isn't very common.
  *
- * And this doesn't even cover the problem of duplicate entries in a tar, which is also quite
- * annoying to handle.
+ * And then there are duplicate entries in a tar, where the last entry wins. The caller may have
+ * already indexed an earlier entry by the time we see the later one, so it has to be told to
+ * forget about it again (the replace_cb of FileList::read()). When reading the archive a second
+ * time, the earlier entries for a path are skipped.
  *
  * What annoys me the most about all of this is that it's not possible to stream an archive from
  * the network and read/index the entire thing in a single step. Now we either have to buffer
@@ -36,6 +42,9 @@ use archive::{walk,ArchiveEntry,FileType};
  * save much)
  *
  * (* So apparently some man pages are close to 10MB...)
+ *
+ * (FileList::spill() does implement a limited form of that, by only buffering small files in man
+ * directories. It's not enabled by default)
  */
 
 /* How links are handled by libarchive and how we deal with that:
@@ -96,44 +105,137 @@ pub struct FileList {
     seen: HashMap<String, EntryType>,
     // List of interesting links
     links: Vec<String>,
+    // Number of earlier entries for paths that occur more than once in the archive
+    dups: HashMap<String, usize>,
+    spill: Option<Spill>,
 }
 
-pub struct MissedFiles(HashMap<String, Vec<String>>);
+// Missed file -> (number of earlier entries with the same path to skip, interesting paths)
+pub struct MissedFiles(HashMap<String, (usize, Vec<String>)>, Option<Spill>);
+
+
+// Temporary directory with copies of uninteresting files, see FileList::spill(). The directory is
+// removed when this struct is dropped.
+struct Spill {
+    dir: PathBuf,
+    max: usize,
+    cnt: usize,
+    files: HashMap<String, PathBuf>,
+}
+
+static SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Spill {
+    fn new(max: usize) -> Result<Spill> {
+        let dir = env::temp_dir().join(format!("manned-indexer-spill-{}-{}", process::id(), SPILL_ID.fetch_add(1, Ordering::SeqCst)));
+        create_dir(&dir)?;
+        Ok(Spill { dir: dir, max: max, cnt: 0, files: HashMap::new() })
+    }
+
+    // Only files below a "man" directory are likely to be the target of a man page link.
+    fn wants(&self, path: &str, size: usize) -> bool {
+        size <= self.max && path.split('/').rev().skip(1).any(|c| c == "man")
+    }
+
+    fn add<T: Entry>(&mut self, path: &str, e: &mut T) -> Result<()> {
+        let fn_ = self.dir.join(format!("{}", self.cnt));
+        self.cnt += 1;
+        copy(e, &mut File::create(&fn_)?)?;
+        self.files.insert(path.to_string(), fn_);
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &str) {
+        if let Some(f) = self.files.remove(path) {
+            let _ = remove_file(f);
+        }
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Err(e) = remove_dir_all(&self.dir) {
+            warn!("Unable to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
 
 
 impl FileList {
 
-    /* Read an archive until the end. Accepts two callbacks:
+    pub fn new() -> FileList {
+        FileList {
+            seen: HashMap::new(),
+            links: Vec::new(),
+            dups: HashMap::new(),
+            spill: None,
+        }
+    }
+
+    /* Enable spilling: Regular files below a "man" directory that are not interesting, but not
+     * larger than 'max' bytes, are copied to a temporary directory while reading the archive. If
+     * a link to such a file is found, its contents can then be read with
+     * MissedFiles::read_spilled() rather than by reading the archive again.
+     */
+    pub fn spill(&mut self, max: usize) -> Result<()> {
+        self.spill = Some(Spill::new(max)?);
+        Ok(())
+    }
+
+    /* Read an archive until the end. Accepts four callbacks:
      *
      *   interest_cb: Called on every path in the archive, should return whether the file is
      *       interesting (i.e. whether we want to know its contents).
      *   entry_cb: Called once on every entry in the archive.
      *   file_cb: Called on every regular file for which interest_cb() showed an interest.
      *       The callback accepts multiple path names, but this function will only provide one.
+     *   replace_cb: Called when a path that has already been passed to file_cb() occurs again in
+     *       the archive. As with tar, the last entry wins, so the caller should forget about the
+     *       earlier file. If the new entry is interesting, it is passed to file_cb() afterwards.
      *
      * Returns a FileList struct that can be used to retreive all interesting non-regular files.
      */
-    pub fn read<F,G,H>(ent: Option<ArchiveEntry>, interest_cb: F, mut entry_cb: G, mut file_cb: H) -> Result<FileList>
-        where F: Fn(&str) -> bool, G: FnMut(&ArchiveEntry), H: FnMut(&[&str], &mut ArchiveEntry) -> Result<()>
+    pub fn read<T,F,G,H,R>(ent: Option<T>, interest_cb: F, entry_cb: G, file_cb: H, replace_cb: R) -> Result<FileList>
+        where T: Entry, F: Fn(&str) -> bool, G: FnMut(&T), H: FnMut(&[&str], &mut T) -> Result<()>, R: FnMut(&str)
     {
-        let mut fl = FileList {
-            seen: HashMap::new(),
-            links: Vec::new(),
-        };
+        let mut fl = FileList::new();
+        fl.add(ent, |_| true, interest_cb, entry_cb, file_cb, replace_cb)?;
+        Ok(fl)
+    }
 
-        try!(walk(ent, |mut e| {
+    /* Same as read(), but adds the entries of an archive to an existing FileList. This is used for
+     * layered archives, where links may resolve to files in a different layer. Entries for which
+     * include_cb() returns false are skipped entirely, as if they were not in the archive.
+     */
+    pub fn add<T,E,F,G,H,R>(&mut self, ent: Option<T>, include_cb: E, interest_cb: F, mut entry_cb: G, mut file_cb: H, mut replace_cb: R) -> Result<()>
+        where T: Entry, E: Fn(&str) -> bool, F: Fn(&str) -> bool, G: FnMut(&T), H: FnMut(&[&str], &mut T) -> Result<()>, R: FnMut(&str)
+    {
+        let fl = self;
+
+        walk(ent, |mut e| {
             let path = match e.path() {
                 Some(x) => x.to_string(),
-                None => { warn!("Invalid UTF-8 filename in archive"); return Ok(true) }
+                None => { warn!("Archive entry without a file name"); return Ok(true) }
             };
+            if !include_cb(&path) {
+                return Ok(true);
+            }
             let ft = e.filetype();
             trace!("Archive entry: {:10} #{} {} {:?}", e.size(), e.nlink(), path, ft);
 
-            // We ought to throw away the result of the previous entry with the same name and use
-            // this new entry instead, but fuck it. This case is too rare, so let's just warn.
-            if let Some(_) = fl.seen.get(&path) {
-                warn!("Duplicate file entry: {}", path);
-                return Ok(true);
+            // The last entry with the same name wins, so throw away whatever we know about the
+            // previous one.
+            if let Some(old) = fl.seen.remove(&path) {
+                debug!("Duplicate file entry: {}", path);
+                *fl.dups.entry(path.clone()).or_insert(0) += 1;
+                match old {
+                    EntryType::Handled => replace_cb(&path),
+                    EntryType::Link(_) => fl.links.retain(|p| p != &path),
+                    _ => (),
+                }
+                if let Some(ref mut s) = fl.spill {
+                    s.remove(&path);
+                }
             }
 
             entry_cb(&e);
@@ -147,6 +249,11 @@ impl FileList {
                         try!(file_cb(&pathv[..], &mut e));
                         EntryType::Handled
                     } else {
+                        if let Some(ref mut s) = fl.spill {
+                            if s.wants(&path, e.size()) {
+                                s.add(&path, e)?;
+                            }
+                        }
                         EntryType::Regular
                     }
                 },
@@ -173,8 +280,7 @@ impl FileList {
 
             fl.seen.insert(path, et);
             Ok(true)
-        }));
-        Ok(fl)
+        })
     }
 
 
@@ -260,7 +366,7 @@ impl FileList {
      * If there are any interesting links that have not yet been passed to file_cb(), a MissedFiles
      * struct is returned that can be used to retrieve those files by re-reading the archive.
      */
-    pub fn links<F>(self, mut cb: F) -> Option<MissedFiles> where F: FnMut(&str, &str) {
+    pub fn links<F>(mut self, mut cb: F) -> Option<MissedFiles> where F: FnMut(&str, &str) {
         let mut missed = HashMap::new();
 
         for p in self.links.iter() {
@@ -273,7 +379,8 @@ impl FileList {
                 },
                 Some((EntryType::Regular, d)) => {
                     let dstr = d.join("/");
-                    missed.entry(dstr).or_insert_with(Vec::new).push(p.to_string());
+                    let skip = self.dups.get(&dstr).cloned().unwrap_or(0);
+                    missed.entry(dstr).or_insert_with(|| (skip, Vec::new())).1.push(p.to_string());
                 },
                 Some((EntryType::Hardlink, _)) =>
                     error!("Interesting link resolved to uninteresting hardlink: {}-> {}", p, dest),
@@ -282,7 +389,7 @@ impl FileList {
         }
 
         if missed.len() > 0 {
-            Some(MissedFiles(missed))
+            Some(MissedFiles(missed, self.spill.take()))
         } else {
             None
         }
@@ -291,30 +398,129 @@ impl FileList {
 
 
 impl MissedFiles {
+    /* Calls file_cb() on every missed file that has a copy in the spill directory (see
+     * FileList::spill()). Returns the files that are still missing, if any, these can only be
+     * found by reading the archive again. */
+    pub fn read_spilled<G>(mut self, mut file_cb: G) -> Result<Option<MissedFiles>>
+        where G: FnMut(&[&str], &mut File) -> Result<()>
+    {
+        if let Some(ref s) = self.1 {
+            let mut done = Vec::new();
+            for (path, &(_, ref links)) in self.0.iter() {
+                if let Some(f) = s.files.get(path) {
+                    let v: Vec<&str> = links.iter().map(|x| x as &str).collect();
+                    try!(file_cb(&v, &mut File::open(f)?));
+                    done.push(path.clone());
+                }
+            }
+            for p in done {
+                self.0.remove(&p);
+            }
+        }
+        Ok(if self.0.len() > 0 { Some(self) } else { None })
+    }
+
     /* Reads the archive again and calls file_cb() on every interesting file that was missed during
      * the first read of the archive (using FileList::{read,links}). file_cb is exactly the same as
      * in FileList::read, but this time it can actually get multiple paths as first argument; which
      * happens when multiple interesting links point to the same file. */
-    pub fn read<G>(mut self, ent: Option<ArchiveEntry>, mut file_cb: G) -> Result<()>
-        where G: FnMut(&[&str], &mut ArchiveEntry) -> Result<()>
+    pub fn read<T,G>(mut self, ent: Option<T>, file_cb: G) -> Result<()>
+        where T: Entry, G: FnMut(&[&str], &mut T) -> Result<()>
     {
+        self.read_from(ent, |_| true, file_cb)
+    }
+
+    /* Same as read(), but for layered archives: Can be called on each layer, with include_cb()
+     * having the same function as in FileList::add(). */
+    pub fn read_from<T,E,G>(&mut self, ent: Option<T>, include_cb: E, mut file_cb: G) -> Result<()>
+        where T: Entry, E: Fn(&str) -> bool, G: FnMut(&[&str], &mut T) -> Result<()>
+    {
+        let missed = &mut self.0;
         walk(ent, |mut e| {
-            if let Some(f) = e.path().and_then(|p| self.0.remove(p)) {
+            // Only the last entry for a path counts, skip the earlier ones.
+            let f = match e.path() {
+                Some(p) if include_cb(p) => match missed.get_mut(p) {
+                    Some(&mut (ref mut skip, _)) if *skip > 0 => { *skip -= 1; None },
+                    Some(_) => missed.remove(p).map(|(_, f)| f),
+                    None => None,
+                },
+                _ => None,
+            };
+            if let Some(f) = f {
                 let v: Vec<&str> = f.iter().map(|x| x as &str).collect();
                 try!(file_cb(&v, &mut e))
             }
-            Ok(self.0.len() > 0)
+            Ok(missed.len() > 0)
         })
     }
 }
 
 
+/* Layered archives, as used by container images: Each layer is an archive that is applied on top
+ * of the previous layers. Files in later layers replace those in earlier layers, and deletions
+ * are represented with whiteout files:
+ *
+ *   <dir>/.wh.<name>    - Removes <dir>/<name> (and anything below it) from earlier layers
+ *   <dir>/.wh..wh..opq  - Removes everything in <dir> from earlier layers
+ *
+ * Handling this in a single pass is not possible, since a file can be removed by a later layer
+ * after we've already indexed it. So a LayerList is created first by listing all layers, after
+ * which the layers can be read again with FileList::add() with visible() as include_cb.
+ */
+pub struct LayerList(HashMap<String, usize>);
+
+
+impl LayerList {
+    pub fn new() -> LayerList {
+        LayerList(HashMap::new())
+    }
+
+    // Add the listing of a layer. Must be called in order, starting with layer 0.
+    pub fn add<T: Entry>(&mut self, layer: usize, ent: Option<T>) -> Result<()> {
+        let mut paths = Vec::new();
+        let mut whiteouts = Vec::new();
+
+        try!(walk(ent, |e| {
+            let path = match e.path() { Some(x) => x.to_string(), None => return Ok(true) };
+            let (dir, name) = match path.rfind('/') {
+                Some(i) => (&path[..i+1], &path[i+1..]),
+                None => ("", &path[..]),
+            };
+            if name == ".wh..wh..opq" {
+                whiteouts.push(dir.to_string());
+            } else if name.starts_with(".wh.") {
+                whiteouts.push(format!("{}{}", dir, &name[4..]));
+            } else {
+                paths.push(path.clone());
+            }
+            Ok(true)
+        }));
+
+        // Whiteouts only apply to lower layers, a layer can add new files in an opaque directory.
+        for w in whiteouts {
+            let dir = if w.ends_with('/') { w.clone() } else { format!("{}/", w) };
+            self.0.retain(|p, l| *l == layer || (p != &w && !p.starts_with(&dir)));
+        }
+        for p in paths {
+            self.0.insert(p, layer);
+        }
+        Ok(())
+    }
+
+    // Whether the given path in the given layer is part of the final filesystem.
+    pub fn visible(&self, layer: usize, path: &str) -> bool {
+        self.0.get(path) == Some(&layer)
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use archive::Archive;
     use std::io::Read;
     use std::fs::File;
+    use std::cell::RefCell;
 
     fn test_read() -> FileList {
         let mut f = File::open("tests/testarchive.tar.xz").unwrap();
@@ -333,7 +539,8 @@ mod tests {
                 e.read_to_string(&mut cont).unwrap();
                 assert_eq!(&cont, "Hello World\n");
                 Ok(())
-            }
+            },
+            |p| panic!("Unexpected replace: {}", p)
         ).unwrap()
     }
 
@@ -407,12 +614,80 @@ mod tests {
                 assert_eq!(&r.0, a);
                 assert_eq!(&r.1, b);
             };
-            res("man/man3/needreread.3", "Potentially interesting file\n");
-            res("man/man6/needreread.6", "Potentially interesting file\n");
+            res("man/man3/needreread.3", "Overwritten file\n");
+            res("man/man6/needreread.6", "Overwritten file\n");
         }
         assert_eq!(files.len(), 0);
     }
 
+    #[test]
+    fn test_spill() {
+        let read = |max| {
+            let mut f = File::open("tests/testarchive.tar.xz").unwrap();
+            let mut fl = FileList::new();
+            fl.spill(max).unwrap();
+            fl.add(Archive::open_archive(&mut f).unwrap(), |_| true, |p| p.starts_with("man/man"), |_| (), |_,_| Ok(()), |_| ()).unwrap();
+            fl.links(|_,_| ()).unwrap()
+        };
+
+        let mut files = Vec::new();
+        let missed = read(1024).read_spilled(|p,f| {
+            let mut cont = String::new();
+            f.read_to_string(&mut cont).unwrap();
+            files.extend(p.iter().map(|x| (x.to_string(), cont.clone()) ));
+            Ok(())
+        }).unwrap();
+        assert!(missed.is_none());
+        files.sort();
+        assert_eq!(files, vec![
+            ("man/man3/needreread.3".to_string(), "Overwritten file\n".to_string()),
+            ("man/man6/needreread.6".to_string(), "Overwritten file\n".to_string()),
+        ]);
+
+        // Too large to spill, needs a second read
+        let missed = read(10).read_spilled(|_,_| panic!()).unwrap();
+        test_reread(missed.unwrap());
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let read = || {
+            let mut f = File::open("tests/duplicates.tar.xz").unwrap();
+            Archive::open_archive(&mut f).unwrap()
+        };
+
+        let mut files = HashMap::new();
+        let mut links = Vec::new();
+        let missed = {
+            let files = RefCell::new(&mut files);
+            FileList::read(read(), |p| p.starts_with("man/man"), |_| (),
+                |p, e| {
+                    let mut cont = String::new();
+                    e.read_to_string(&mut cont).unwrap();
+                    assert!(files.borrow_mut().insert(p[0].to_string(), cont).is_none());
+                    Ok(())
+                },
+                |p| assert!(files.borrow_mut().remove(p).is_some())
+            ).unwrap().links(|p,d| links.push((p.to_string(), d.to_string())))
+        };
+        let mut files: Vec<(String, String)> = files.into_iter().collect();
+        files.sort();
+        assert_eq!(files, vec![
+            ("man/man1/dup.1".to_string(), "Second version\n".to_string()),
+            ("man/man1/fromlink.1".to_string(), "No longer a link\n".to_string()),
+        ]);
+        assert_eq!(links, vec![("man/man1/tolink.1".to_string(), "man/man1/dup.1".to_string())]);
+
+        let mut files = Vec::new();
+        missed.unwrap().read(read(), |p,e| {
+            let mut cont = String::new();
+            e.read_to_string(&mut cont).unwrap();
+            files.push((p.to_vec().join(","), cont));
+            Ok(())
+        }).unwrap();
+        assert_eq!(files, vec![("man/man1/needreread.1".to_string(), "New target\n".to_string())]);
+    }
+
     #[test]
     fn test_reader() {
         //use env_logger;
@@ -0,0 +1,130 @@
+use std::str;
+use regex::Regex;
+use encoding;
+use encoding::{all,EncodingRef};
+use encoding::label::encoding_from_whatwg_label;
+
+
+// Guess the character encoding used for a locale, e.g. "ja_JP.eucJP" or "ru".
+pub fn encoding(locale: &str) -> Option<EncodingRef> {
+    let locale = locale.to_lowercase();
+
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^(?x)
+           ([a-z]+)           # primary language
+           (?:_  ([a-z]+))?   # secondary language
+           (?:@  [a-z]+)?     # script (potentially useful, but uncommon and not currently used)
+           (?:\. ([^\.@]+))?  # encoding (FUCKING USEFUL)
+        $").unwrap();
+    }
+
+    let cap = match RE.captures(&locale) { Some(x) => x, None => return None };
+    let lang = &cap[1];
+    let seclang = cap.get(2).map(|e| e.as_str());
+    let enc = cap.get(3).map(|e| e.as_str());
+
+    // Try to do something with the encoding tag
+    match (lang, enc) {
+        (_,    Some("eucjp")) |
+        (_,    Some("ujis")) | // Not sure about this one, but it seems to come out alright
+        ("ja", Some("euc")) => return Some(all::EUC_JP),
+
+        (_,    Some("euckr")) => return Some(all::WINDOWS_949),
+
+        /* Not sure if PCK is just an alias for SJIS or if there's more of a difference, but it
+         * certainly looks like a SJIS-like encoding. */
+        ("ja", Some("pck")) => return Some(all::WINDOWS_31J),
+
+        /* This is apparently some variant of ISO-2022-JP */
+        ("ja", Some("jis7")) => return Some(all::ISO_2022_JP),
+
+        (_,    Some(x)) => match encoding_from_whatwg_label(x) {
+            Some(x) => return Some(x),
+            _ => { warn!("Unknown encoding in locale: {}", x) },
+        },
+        _ => {},
+    };
+
+    // Fall back to language
+    match (lang, seclang) {
+        ("pl", _) |
+        ("cs", _) |
+        ("hr", _) |
+        ("hu", _) |
+        ("sl", _) |
+        ("sk", _) => Some(all::ISO_8859_2),
+        ("bg", _) |
+        ("be", _) |
+        ("uk", _) => Some(all::ISO_8859_5),
+        ("el", _) => Some(all::ISO_8859_7),
+        ("et", _) => Some(all::ISO_8859_15),
+        ("tr", _) => Some(all::WINDOWS_1254),
+        ("ru", _) => Some(all::KOI8_R),
+        ("ja", _) |
+        ("jp", _) => Some(all::EUC_JP), // Tricky; but JIS is certainly less common
+        ("zh", Some("cn")) => Some(all::GBK),  // These are based purely on what I've observed,
+        ("zh", _) => Some(all::BIG5_2003),     // perhaps some heuristics based on contents can do better
+        ("ko", _) => Some(all::WINDOWS_949),
+        (_, _) => None,
+    }
+}
+
+
+// The locale directory of a man page path, as in "man/<locale>/man1/". Same as the locale returned
+// by man::parse_path(), but also works for directories and symlink targets.
+fn path_locale(path: &str) -> Option<&str> {
+    let comp: Vec<&str> = path.split('/').collect();
+    comp.windows(3).rev()
+        .find(|w| w[0].ends_with("man") && w[2].len() == 4 && w[2].starts_with("man"))
+        .map(|w| w[1])
+}
+
+
+/* Decodes a file name from an archive. Names that aren't valid UTF-8 are decoded with the encoding
+ * suggested by the locale in the path, or otherwise in 'ctx' (e.g. the path of a symlink when
+ * decoding its target), falling back to ISO-8859-1. The locale directory itself is almost always
+ * ASCII, so a lossy conversion is good enough to find it.
+ */
+pub fn decode_path(path: &[u8], ctx: &[u8]) -> String {
+    if let Ok(s) = str::from_utf8(path) {
+        return s.to_string();
+    }
+    for e in [path, ctx].iter().filter_map(|p| path_locale(&String::from_utf8_lossy(p)).and_then(encoding)) {
+        if let Ok(s) = e.decode(path, encoding::DecoderTrap::Strict) {
+            return s;
+        }
+    }
+    (all::ISO_8859_1 as EncodingRef).decode(path, encoding::DecoderTrap::Ignore).unwrap()
+}
+
+
+// Decodes a file name from a directory listing and joins it to the (decoded) path of the directory.
+pub fn join_path(dir: &str, name: &[u8]) -> String {
+    let name = decode_path(name, &[dir.as_bytes(), b"/", name].concat());
+    if dir == "" { name } else { format!("{}/{}", dir, name) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_path() {
+        assert_eq!(decode_path(b"man/man1/ls.1", b""), "man/man1/ls.1");
+        // "テスト" in EUC-JP
+        assert_eq!(decode_path(b"man/ja_JP.eucJP/man1/\xa5\xc6\xa5\xb9\xa5\xc8.1", b""), "man/ja_JP.eucJP/man1/テスト.1");
+        assert_eq!(decode_path(b"\xa5\xc6\xa5\xb9\xa5\xc8.1", b"man/ja/man1/link.1"), "テスト.1");
+        // "тест" in KOI8-R
+        assert_eq!(decode_path(b"man/ru/man1/\xd4\xc5\xd3\xd4.1", b""), "man/ru/man1/тест.1");
+        assert_eq!(decode_path(b"usr/share/man/man1/H\xe9ll\xf6.1", b""), "usr/share/man/man1/Héllö.1");
+        assert_eq!(decode_path(b"H\xe9ll\xf6.txt", b""), "Héllö.txt");
+    }
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(join_path("", b"usr"), "usr");
+        assert_eq!(join_path("man/ru/man1", b"\xd4\xc5\xd3\xd4.1"), "man/ru/man1/тест.1");
+        assert_eq!(join_path("man/ru", b"man1"), "man/ru/man1");
+    }
+}
@@ -9,21 +9,39 @@ extern crate ring;
 extern crate encoding;
 extern crate postgres;
 extern crate hyper;
+extern crate hyper_native_tls;
 extern crate url;
 extern crate chrono;
 extern crate quick_xml;
+extern crate serde_json;
 
 mod archive;
 mod archread;
+mod locale;
 mod man;
+mod nar;
 mod open;
 mod pkg;
+mod squashfs;
+mod sys_alpine;
 mod sys_arch;
+mod sys_brew;
+mod sys_bsd;
+mod sys_conda;
 mod sys_deb;
+mod sys_dir;
 mod sys_freebsd1;
 mod sys_freebsd2;
+mod sys_freebsdbase;
+mod sys_gentoo;
+mod sys_iso;
+mod sys_nix;
+mod sys_oci;
 mod sys_rpmdir;
 mod sys_rpm;
+mod sys_slackware;
+mod sys_snap;
+mod sys_void;
 
 
 // Convenience function to get a system id by short-name. Panics if the system doesn't exist.
@@ -41,6 +59,11 @@ fn main() {
         (about: "Manned.org man page indexer")
         (@arg v: -v +multiple "Increase verbosity")
         (@arg dry: --dryrun "Don't actually download and index packages")
+        (@arg cachedir: --("cache-dir") +takes_value "Download cache directory (default: /var/tmp/manned-indexer)")
+        (@arg cachesize: --("cache-size") +takes_value "Maximum size of the download cache in MiB (default: 10240)")
+        (@arg cachepkgs: --("cache-packages") "Cache downloaded packages, not just repository metadata")
+        (@arg jobs: -j --jobs +takes_value "Number of packages to download and index in parallel (default: 1)")
+        (@arg spill: --("spill-size") +takes_value "Copy uninteresting files in man directories up to this size in KiB to a temporary directory, so that links to them can be resolved without reading the package twice (default: 0, disabled)")
         (@subcommand pkg =>
             (about: "Index a single package")
             (@arg force: --force "Overwrite existing indexed package")
@@ -55,38 +78,137 @@ fn main() {
         (@subcommand arch =>
             (about: "Index an Arch Linux repository")
             (@arg sys: --sys +required +takes_value "System short-name")
-            (@arg mirror: --mirror +required +takes_value "Mirror URL")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL")
             (@arg repo: --repo +required +takes_value "Repository name")
         )
+        (@subcommand apk =>
+            (about: "Index an Alpine Linux repository")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to a release dir, e.g. .../alpine/v3.8)")
+            (@arg repo: --repo +required +takes_value "Repository name")
+            (@arg arch: --arch +required +takes_value "Arch")
+        )
+        (@subcommand brew =>
+            (about: "Index Homebrew bottles")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for all packages (e.g. the tap name)")
+            (@arg dir: --dir +takes_value "Local directory with bottles")
+            (@arg json: --json +takes_value "JSON formula dump (file or URL)")
+            (@arg mirror: --mirror +takes_value +multiple number_of_values(1) "Bottle mirror URL, used with --json instead of the bottle URLs in the dump")
+        )
+        (@subcommand bsdsets =>
+            (about: "Index the base sets of an OpenBSD or NetBSD release")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg ver: --ver +required +takes_value "Release version")
+            (@arg arch: --arch +required +takes_value "Arch")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the dir with the sets)")
+        )
+        (@subcommand bsdpkg =>
+            (about: "Index an OpenBSD or NetBSD (pkgsrc) package directory")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for packages without a pkgpath")
+            (@arg arch: --arch +required +takes_value "Arch")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the dir with the .tgz files)")
+        )
+        (@subcommand conda =>
+            (about: "Index a Conda channel subdirectory")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for all packages (e.g. the channel name)")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the dir with repodata.json, e.g. conda-forge/linux-64/)")
+        )
         (@subcommand deb =>
             (about: "Index a Debian repository")
             (@arg sys: --sys +required +takes_value "System short-name")
-            (@arg mirror: --mirror +required +takes_value "Mirror URL")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL")
+            (@arg suite: --suite +takes_value "Suite to index, components and files are read from its (In)Release file")
+            (@arg arch: --arch +takes_value "Only index this architecture (with --suite)")
             (@arg contents: --contents +takes_value "Contents file")
-            (@arg packages: --packages +required +takes_value "Packages file")
+            (@arg packages: --packages +takes_value "Packages file (required without --suite)")
+        )
+        (@subcommand dir =>
+            (about: "Index the man pages in a local directory as a single package")
+            (@arg force: --force "Overwrite existing indexed package")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Package category")
+            (@arg pkg: --pkg +required +takes_value "Package name")
+            (@arg ver: --ver +required +takes_value "Package version")
+            (@arg date: --date +takes_value "Package release date (defaults to the last modification time of the man pages)")
+            (@arg arch: --arch +takes_value "Architecture")
+            (@arg root: --root +takes_value "Root directory, paths and absolute symlinks are relative to this (default: /)")
+            (@arg DIR: +required "Directory to index")
         )
         (@subcommand freebsd1 =>
             (about: "Index packages from a FreeBSD <= 9.2 package repo")
             (@arg sys: --sys +required +takes_value "System short-name")
-            (@arg mirror: --mirror +required +takes_value "Mirror URL (should point to the packages/ dir)")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the packages/ dir)")
             (@arg arch: --arch +required +takes_value "Arch")
         )
         (@subcommand freebsd2 =>
             (about: "Index packages from a FreeBSD >= 9.3 package repo")
             (@arg sys: --sys +required +takes_value "System short-name")
-            (@arg mirror: --mirror +required +takes_value "Mirror URL")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL")
+        )
+        (@subcommand freebsd_base =>
+            (name: "freebsd-base")
+            (about: "Index the base system of a FreeBSD >= 9.0 release")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg ver: --ver +required +takes_value "Release version")
+            (@arg arch: --arch +required +takes_value "Arch")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the release dir with the MANIFEST)")
+        )
+        (@subcommand gentoo =>
+            (about: "Index a Gentoo binary package host")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the dir with the Packages file)")
+        )
+        (@subcommand iso =>
+            (about: "Index all packages in an ISO image")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg FILE: +required "ISO image file or URL")
+        )
+        (@subcommand nix =>
+            (about: "Index a local Nix binary cache")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for all packages")
+            (@arg arch: --arch +takes_value "Architecture")
+            (@arg CACHE: +required "Binary cache directory or file:// URL")
+        )
+        (@subcommand oci =>
+            (about: "Index a container image (OCI image layout directory or docker save tarball)")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for all images")
+            (@arg name: --name +takes_value "Package name (defaults to the repository in the image tag, required for OCI layouts)")
+            (@arg FILE: +required "Image directory or tarball")
         )
         (@subcommand rpmdir =>
             (about: "Index a bare RPM directory")
             (@arg sys: --sys +required +takes_value "System short-name")
             (@arg cat: --cat +required +takes_value "Category to set for all packages")
-            (@arg mirror: --mirror +required +takes_value "Mirror URL")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL")
         )
         (@subcommand rpm =>
             (about: "Index an RPM repository")
             (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for packages without a SUSE keyword")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL")
+        )
+        (@subcommand slackware =>
+            (about: "Index a Slackware package tree")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the dir with PACKAGES.TXT and MANIFEST.bz2)")
+        )
+        (@subcommand snap =>
+            (about: "Index all .snap files in a local directory")
+            (@arg sys: --sys +required +takes_value "System short-name")
+            (@arg cat: --cat +required +takes_value "Category to set for all snaps")
+            (@arg DIR: +required "Directory with .snap files")
+        )
+        (@subcommand void =>
+            (about: "Index a Void Linux xbps repository")
+            (@arg sys: --sys +required +takes_value "System short-name")
             (@arg cat: --cat +required +takes_value "Category to set for all packages")
-            (@arg mirror: --mirror +required +takes_value "Mirror URL")
+            (@arg mirror: --mirror +required +takes_value +multiple number_of_values(1) "Mirror URL (should point to the repository dir, e.g. .../current)")
+            (@arg arch: --arch +required +takes_value "Arch")
         )
     ).get_matches();
 
@@ -103,8 +225,30 @@ fn main() {
         .filter(Some("postgres"), if verbose >= 4 { log::LevelFilter::Trace } else { log::LevelFilter::Info })
         .init();
 
-    if let Err(e) = open::clear_cache() {
-        error!("Error clearing cache: {}", e);
+    // --mirror can be given multiple times. The sync functions get the first mirror, the others
+    // are used by open::Path when a file can't be fetched from that one.
+    if let Some(m) = arg.subcommand().1.and_then(|s| s.values_of("mirror")) {
+        open::set_mirrors(m.map(str::to_string).collect());
+    }
+
+    let mut cache = open::CacheOpt::default();
+    if let Some(dir) = arg.value_of("cachedir") {
+        cache.dir = dir.to_string();
+    }
+    if let Some(size) = arg.value_of("cachesize") {
+        cache.size = match size.parse::<u64>() {
+            Ok(x) => x * 1024 * 1024,
+            Err(_) => { error!("Invalid cache size: {}", size); return },
+        };
+    }
+    cache.packages = arg.is_present("cachepkgs");
+
+    match arg.value_of("spill").unwrap_or("0").parse::<usize>() {
+        Ok(x) => pkg::SPILL_SIZE.store(x * 1024, std::sync::atomic::Ordering::Relaxed),
+        Err(_) => { error!("Invalid spill size"); return },
+    }
+    if let Err(e) = open::init_cache(cache) {
+        error!("Error initializing cache: {}", e);
         return;
     }
 
@@ -118,6 +262,21 @@ fn main() {
     };
     trace!("Connected to database");
 
+    let jobs = match arg.value_of("jobs").unwrap_or("1").parse::<usize>() {
+        Ok(x) if x > 0 => x,
+        _ => { error!("Invalid number of jobs"); return },
+    };
+    if jobs > 1 {
+        let mut conns = Vec::new();
+        for _ in 0..jobs {
+            match postgres::Connection::connect(&dbhost[..], postgres::TlsMode::None) {
+                Ok(x) => conns.push(x),
+                Err(x) => { error!("Can't connect to postgres: {}", x); return },
+            }
+        }
+        pkg::start_pool(conns);
+    }
+
     if let Some(matches) = arg.subcommand_matches("pkg") {
         let date = match matches.value_of("date").unwrap() {
             "deb" => pkg::Date::Deb,
@@ -133,6 +292,7 @@ fn main() {
             ver: matches.value_of("ver").unwrap(),
             date: date,
             arch: matches.value_of("arch"),
+            digest: None,
             file: open::Path{ path: matches.value_of("FILE").unwrap(), cache: false, canbelocal: true},
         });
     }
@@ -145,13 +305,84 @@ fn main() {
         );
     }
 
-    if let Some(matches) = arg.subcommand_matches("deb") {
-        sys_deb::sync(&db,
+    if let Some(matches) = arg.subcommand_matches("apk") {
+        sys_alpine::sync(&db,
             sysbyshort(&db, matches.value_of("sys").unwrap()),
             matches.value_of("mirror").unwrap(),
-            matches.value_of("contents").map(|e| { open::Path{ path: e, cache: true, canbelocal: true} }),
-            open::Path{ path: matches.value_of("packages").unwrap(), cache: true, canbelocal: true},
-        );
+            matches.value_of("repo").unwrap(),
+            matches.value_of("arch").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("brew") {
+        sys_brew::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("dir"),
+            matches.value_of("json"),
+            matches.value_of("mirror")
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("bsdsets") {
+        sys_bsd::sets(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("ver").unwrap(),
+            matches.value_of("arch").unwrap(),
+            matches.value_of("mirror").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("bsdpkg") {
+        sys_bsd::pkgs(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("arch").unwrap(),
+            matches.value_of("mirror").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("conda") {
+        sys_conda::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("mirror").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("deb") {
+        if let Some(suite) = matches.value_of("suite") {
+            sys_deb::sync_suite(&db,
+                sysbyshort(&db, matches.value_of("sys").unwrap()),
+                matches.value_of("mirror").unwrap(),
+                suite,
+                matches.value_of("arch")
+            ).unwrap_or_else(|e| error!("{}", e));
+        } else if let Some(packages) = matches.value_of("packages") {
+            sys_deb::sync(&db,
+                sysbyshort(&db, matches.value_of("sys").unwrap()),
+                matches.value_of("mirror").unwrap(),
+                matches.value_of("contents").map(|e| { open::Path{ path: e, cache: true, canbelocal: true} }),
+                open::Path{ path: packages, cache: true, canbelocal: true},
+            );
+        } else {
+            error!("Either --suite or --packages is required");
+        }
+    }
+
+    if let Some(matches) = arg.subcommand_matches("dir") {
+        let dir = matches.value_of("DIR").unwrap();
+        sys_dir::sync(&db, pkg::PkgOpt {
+            force: matches.is_present("force"),
+            sys: sysbyshort(&db, matches.value_of("sys").unwrap()),
+            cat: matches.value_of("cat").unwrap(),
+            pkg: matches.value_of("pkg").unwrap(),
+            ver: matches.value_of("ver").unwrap(),
+            date: matches.value_of("date").map(pkg::Date::Known).unwrap_or(pkg::Date::Max),
+            arch: matches.value_of("arch"),
+            digest: None,
+            file: open::Path{ path: dir, cache: false, canbelocal: true},
+        }, matches.value_of("root").unwrap_or("/"), dir);
     }
 
     if let Some(matches) = arg.subcommand_matches("freebsd1") {
@@ -169,6 +400,47 @@ fn main() {
         ).unwrap_or_else(|e| error!("{}", e));
     }
 
+    if let Some(matches) = arg.subcommand_matches("freebsd-base") {
+        sys_freebsdbase::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("ver").unwrap(),
+            matches.value_of("arch").unwrap(),
+            matches.value_of("mirror").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("gentoo") {
+        sys_gentoo::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("mirror").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("iso") {
+        sys_iso::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("FILE").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("nix") {
+        sys_nix::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("arch"),
+            matches.value_of("CACHE").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("oci") {
+        sys_oci::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("name"),
+            matches.value_of("FILE").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
     if let Some(matches) = arg.subcommand_matches("rpmdir") {
         sys_rpmdir::sync(&db,
             sysbyshort(&db, matches.value_of("sys").unwrap()),
@@ -185,5 +457,30 @@ fn main() {
         ).unwrap_or_else(|e| error!("{}", e));
     }
 
+    if let Some(matches) = arg.subcommand_matches("slackware") {
+        sys_slackware::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("mirror").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("snap") {
+        sys_snap::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("DIR").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    if let Some(matches) = arg.subcommand_matches("void") {
+        sys_void::sync(&db,
+            sysbyshort(&db, matches.value_of("sys").unwrap()),
+            matches.value_of("cat").unwrap(),
+            matches.value_of("mirror").unwrap(),
+            matches.value_of("arch").unwrap()
+        ).unwrap_or_else(|e| error!("{}", e));
+    }
+
+    pkg::finish_pool();
     trace!("Exiting");
 }
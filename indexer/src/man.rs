@@ -9,6 +9,7 @@ use encoding::label::encoding_from_whatwg_label;
 use ring::digest;
 
 use archive::Archive;
+use locale;
 
 // Anything larger than this just isn't a man page. I hope.
 const MAX_MAN_SIZE: u64 = 20*1024*1024;
@@ -72,6 +73,85 @@ pub fn ismanpath(path: &str) -> bool {
 }
 
 
+// Strips the compression extensions that parse_path() accepts.
+pub fn strip_compression(path: &str) -> &str {
+    let mut path = path;
+    while let Some(ext) = [".gz", ".lzma", ".bz2", ".xz"].iter().find(|&e| path.ends_with(e)) {
+        path = &path[..path.len()-ext.len()];
+    }
+    path
+}
+
+
+// If the man page is nothing more than a single ".so" request (ignoring comments and empty lines),
+// returns the path of the included file.
+pub fn so_target(cont: &str) -> Option<&str> {
+    let mut target = None;
+    for line in cont.lines() {
+        let line = line.trim_right();
+        if line == "" || line == "." || line.starts_with(".\\\"") || line.starts_with("'\\\"") || line.starts_with("\\\"") {
+            continue;
+        }
+        if target.is_some() || !line.starts_with(".so ") {
+            return None;
+        }
+        let t = line[4..].trim();
+        if t == "" || t.contains(char::is_whitespace) {
+            return None;
+        }
+        target = Some(t);
+    }
+    target
+}
+
+
+// Join a relative path to a list of directory components, resolving "." and "..".
+fn join_path(dir: &[&str], path: &str) -> Option<String> {
+    let mut comp = dir.to_vec();
+    for c in path.split('/') {
+        match c {
+            "" | "." => (),
+            ".." => { comp.pop()?; },
+            c => comp.push(c),
+        }
+    }
+    Some(comp.join("/"))
+}
+
+
+/* Returns the paths that a ".so" request in the man page at 'path' may refer to, in order of
+ * preference, without compression extensions. The path is normally relative to the root of the
+ * man hierarchy (the parent of the man? directory), but for localized pages it often refers to
+ * the untranslated page instead, and some packages have it relative to the directory of the page
+ * itself.
+ */
+pub fn so_paths(path: &str, target: &str) -> Vec<String> {
+    let target = strip_compression(target);
+    if target.starts_with('/') {
+        return join_path(&[], target).into_iter().collect();
+    }
+    let mut dir: Vec<&str> = path.split('/').collect();
+    dir.pop();
+
+    let mut bases = Vec::new();
+    if dir.len() >= 1 {
+        bases.push(&dir[..dir.len()-1]);
+    }
+    if dir.len() >= 2 && parse_path(path).map(|(_,_,l)| l != "") == Some(true) {
+        bases.push(&dir[..dir.len()-2]);
+    }
+    bases.push(&dir[..]);
+
+    let mut res: Vec<String> = Vec::new();
+    for p in bases.into_iter().filter_map(|b| join_path(b, target)) {
+        if !res.contains(&p) {
+            res.push(p);
+        }
+    }
+    res
+}
+
+
 fn validate(data: &Vec<u8>) -> Option<&'static str> {
     lazy_static! {
         static ref HTML: bytes::Regex = bytes::Regex::new(r"^\s*<(?:html|head|!DOCTYPE)").unwrap();
@@ -128,68 +208,9 @@ fn codec_from_tag(data: &Vec<u8>) -> Option<EncodingRef> {
 
 
 fn codec_from_path(path: &str) -> Option<EncodingRef> {
-    let locale = match parse_path(path) {
-        Some((_,_,l)) if l != "" => l.to_lowercase(),
-        _ => return None,
-    };
-
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^(?x)
-           ([a-z]+)           # primary language
-           (?:_  ([a-z]+))?   # secondary language
-           (?:@  [a-z]+)?     # script (potentially useful, but uncommon and not currently used)
-           (?:\. ([^\.@]+))?  # encoding (FUCKING USEFUL)
-        $").unwrap();
-    }
-
-    let cap = match RE.captures(&locale) { Some(x) => x, None => return None };
-    let lang = &cap[1];
-    let seclang = cap.get(2).map(|e| e.as_str());
-    let enc = cap.get(3).map(|e| e.as_str());
-
-    // Try to do something with the encoding tag
-    match (lang, enc) {
-        (_,    Some("eucjp")) |
-        (_,    Some("ujis")) | // Not sure about this one, but it seems to come out alright
-        ("ja", Some("euc")) => return Some(all::EUC_JP),
-
-        (_,    Some("euckr")) => return Some(all::WINDOWS_949),
-
-        /* Not sure if PCK is just an alias for SJIS or if there's more of a difference, but it
-         * certainly looks like a SJIS-like encoding. */
-        ("ja", Some("pck")) => return Some(all::WINDOWS_31J),
-
-        /* This is apparently some variant of ISO-2022-JP */
-        ("ja", Some("jis7")) => return Some(all::ISO_2022_JP),
-
-        (_,    Some(x)) => match encoding_from_whatwg_label(x) {
-            Some(x) => return Some(x),
-            _ => { warn!("Unknown encoding in locale: {}", x) },
-        },
-        _ => {},
-    };
-
-    // Fall back to language
-    match (lang, seclang) {
-        ("pl", _) |
-        ("cs", _) |
-        ("hr", _) |
-        ("hu", _) |
-        ("sl", _) |
-        ("sk", _) => Some(all::ISO_8859_2),
-        ("bg", _) |
-        ("be", _) |
-        ("uk", _) => Some(all::ISO_8859_5),
-        ("el", _) => Some(all::ISO_8859_7),
-        ("et", _) => Some(all::ISO_8859_15),
-        ("tr", _) => Some(all::WINDOWS_1254),
-        ("ru", _) => Some(all::KOI8_R),
-        ("ja", _) |
-        ("jp", _) => Some(all::EUC_JP), // Tricky; but JIS is certainly less common
-        ("zh", Some("cn")) => Some(all::GBK),  // These are based purely on what I've observed,
-        ("zh", _) => Some(all::BIG5_2003),     // perhaps some heuristics based on contents can do better
-        ("ko", _) => Some(all::WINDOWS_949),
-        (_, _) => None,
+    match parse_path(path) {
+        Some((_,_,l)) if l != "" => locale::encoding(l),
+        _ => None,
     }
 }
 
@@ -285,6 +306,30 @@ fn test_codec_from_path() {
 }
 
 
+#[test]
+fn test_so_target() {
+    assert_eq!(so_target(".so man3/foo.3\n"), Some("man3/foo.3"));
+    assert_eq!(so_target(".\\\" Generated\n'\\\" t\n\n.so  man3/foo.3.gz  \n"), Some("man3/foo.3.gz"));
+    assert_eq!(so_target(".so man3/foo.3\n.so man3/bar.3\n"), None);
+    assert_eq!(so_target(".so man.macros\n.TH FOO 1\n"), None);
+    assert_eq!(so_target(".sofoo\n"), None);
+    assert_eq!(so_target(".TH FOO 1\n"), None);
+}
+
+
+#[test]
+fn test_so_paths() {
+    assert_eq!(so_paths("usr/share/man/man3/bar.3.gz", "man3/foo.3"), vec![
+        "usr/share/man/man3/foo.3", "usr/share/man/man3/man3/foo.3"]);
+    assert_eq!(so_paths("usr/share/man/de/man1/bar.1", "man1/foo.1.gz"), vec![
+        "usr/share/man/de/man1/foo.1", "usr/share/man/man1/foo.1", "usr/share/man/de/man1/man1/foo.1"]);
+    assert_eq!(so_paths("usr/share/man/man3/bar.3", "./foo.3"), vec![
+        "usr/share/man/foo.3", "usr/share/man/man3/foo.3"]);
+    assert_eq!(so_paths("man/man1/bar.1", "../../../foo.1"), Vec::<String>::new());
+    assert_eq!(so_paths("usr/share/man/man1/bar.1", "/usr/share/man/man1/foo.1"), vec!["usr/share/man/man1/foo.1"]);
+}
+
+
 #[test]
 fn test_decode_zh() {
     use std::fs::File;
@@ -0,0 +1,302 @@
+use std::io::{Result,Error,ErrorKind,Read,copy,sink};
+
+use archive::{Entry,FileType};
+use locale;
+
+
+/* Streaming reader for the Nix ARchive format, as produced by "nix-store --dump". Every token is a
+ * string: a 64-bit little-endian length followed by the bytes, padded with zeros to a multiple of
+ * 8 bytes. The structure is:
+ *
+ *   archive   := "nix-archive-1" node
+ *   node      := "(" "type" type ")"
+ *   type      := "regular" [ "executable" "" ] "contents" <data>
+ *              | "symlink" "target" <target>
+ *              | "directory" entry*
+ *   entry     := "entry" "(" "name" <name> "node" node ")"
+ *
+ * Entries are returned in the same way as archive::ArchiveEntry. Path names are relative to the
+ * root of the store path, names and symlink targets that aren't valid UTF-8 are decoded with
+ * locale::decode_path(). NAR files don't have timestamps, so mtime() is always 0.
+ *
+ * Reference: Figure 5.2 in Eelco Dolstra's PhD thesis, "The Purely Functional Software Deployment
+ * Model".
+ */
+
+pub struct NarEntry<'a> {
+    rd: &'a mut Read,
+    // Directories we're in, as their full path. The root is an empty string.
+    stack: Vec<String>,
+    path: String,
+    ft: FileType,
+    size: u64,
+    remaining: u64,
+    pad: u64,
+}
+
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid NAR file: {}", msg))
+}
+
+fn padding(len: u64) -> u64 {
+    (8 - len % 8) % 8
+}
+
+fn read_u64(rd: &mut Read) -> Result<u64> {
+    let mut b = [0u8; 8];
+    rd.read_exact(&mut b)?;
+    Ok(b.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+}
+
+fn read_str(rd: &mut Read, max: u64) -> Result<Vec<u8>> {
+    let len = read_u64(rd)?;
+    if len > max {
+        return Err(invalid("string too long"));
+    }
+    let mut buf = vec![0u8; (len + padding(len)) as usize];
+    rd.read_exact(&mut buf)?;
+    buf.truncate(len as usize);
+    Ok(buf)
+}
+
+fn expect(rd: &mut Read, tok: &str) -> Result<()> {
+    if read_str(rd, 64)? == tok.as_bytes() {
+        Ok(())
+    } else {
+        Err(invalid(&format!("expected '{}'", tok)))
+    }
+}
+
+// Reads the start of a node at 'path', up to the contents of a regular file. Returns the file type
+// and size.
+fn node(rd: &mut Read, path: &str) -> Result<(FileType, u64)> {
+    expect(rd, "(")?;
+    expect(rd, "type")?;
+    match &read_str(rd, 64)?[..] {
+        b"regular" => {
+            let mut tok = read_str(rd, 64)?;
+            if tok == b"executable" {
+                expect(rd, "")?;
+                tok = read_str(rd, 64)?;
+            }
+            if tok != b"contents" {
+                return Err(invalid("expected 'contents'"));
+            }
+            Ok((FileType::File, read_u64(rd)?))
+        },
+        b"symlink" => {
+            expect(rd, "target")?;
+            let target = read_str(rd, 4096)?;
+            Ok((FileType::Link(locale::decode_path(&target, path.as_bytes())), 0))
+        },
+        b"directory" => Ok((FileType::Directory, 0)),
+        _ => Err(invalid("unknown node type")),
+    }
+}
+
+
+impl<'a> NarEntry<'a> {
+    // Returns None if the archive is empty or if it's just a single file.
+    pub fn open(rd: &'a mut Read) -> Result<Option<NarEntry<'a>>> {
+        expect(rd, "nix-archive-1")?;
+        if node(rd, "")?.0 != FileType::Directory {
+            return Ok(None);
+        }
+        NarEntry {
+            rd: rd,
+            stack: vec![String::new()],
+            path: String::new(),
+            ft: FileType::Directory,
+            size: 0,
+            remaining: 0,
+            pad: 0,
+        }.entries()
+    }
+
+    // Read the next entry in the current directory, or the next one after that if the directory
+    // has ended.
+    fn entries(mut self) -> Result<Option<NarEntry<'a>>> {
+        loop {
+            let tok = read_str(self.rd, 64)?;
+            if tok == b")" {
+                self.stack.pop();
+                if self.stack.is_empty() {
+                    return Ok(None);
+                }
+                expect(self.rd, ")")?;
+                continue;
+            }
+            if tok != b"entry" {
+                return Err(invalid("expected 'entry'"));
+            }
+            expect(self.rd, "(")?;
+            expect(self.rd, "name")?;
+            let name = read_str(self.rd, 255)?;
+            if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+                return Err(invalid("bad file name"));
+            }
+            self.path = locale::join_path(self.stack.last().map(|p| &p[..]).unwrap_or(""), &name);
+            expect(self.rd, "node")?;
+            let (ft, size) = node(self.rd, &self.path)?;
+
+            self.ft = ft;
+            self.size = size;
+            self.remaining = size;
+            self.pad = padding(size);
+            return Ok(Some(self));
+        }
+    }
+}
+
+
+impl<'a> Entry for NarEntry<'a> {
+    fn next(mut self) -> Result<Option<NarEntry<'a>>> {
+        let skip = self.remaining + self.pad;
+        if copy(&mut (&mut *self.rd).take(skip), &mut sink())? != skip {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of NAR file"));
+        }
+        self.remaining = 0;
+        self.pad = 0;
+
+        if self.ft == FileType::Directory {
+            let p = self.path.clone();
+            self.stack.push(p);
+        } else {
+            expect(self.rd, ")")?; // end of node
+            expect(self.rd, ")")?; // end of entry
+        }
+        self.entries()
+    }
+
+    fn path(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+
+    fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn mtime(&self) -> i64 {
+        0
+    }
+
+    fn filetype(&self) -> FileType {
+        match self.ft {
+            FileType::File => FileType::File,
+            FileType::Directory => FileType::Directory,
+            FileType::Link(ref l) => FileType::Link(l.clone()),
+            FileType::Other => FileType::Other,
+        }
+    }
+}
+
+
+impl<'a> Read for NarEntry<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = if (buf.len() as u64) < self.remaining { buf.len() } else { self.remaining as usize };
+        let len = self.rd.read(&mut buf[..len])?;
+        if len == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Unexpected end of NAR file"));
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use archive::walk;
+
+    fn s(buf: &mut Vec<u8>, v: &[u8]) {
+        let len = v.len() as u64;
+        buf.extend((0..8).map(|i| (len >> (i*8)) as u8));
+        buf.extend_from_slice(v);
+        buf.extend((0..padding(len)).map(|_| 0u8));
+    }
+
+    fn nar(toks: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for t in toks {
+            s(&mut buf, t);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_read() {
+        let data = nar(&[b"nix-archive-1", b"(", b"type", b"directory",
+            b"entry", b"(", b"name", b"bin", b"node", b"(", b"type", b"directory",
+                b"entry", b"(", b"name", b"ls", b"node", b"(", b"type", b"regular", b"executable", b"", b"contents", b"ELF!", b")", b")",
+            b")", b")",
+            b"entry", b"(", b"name", b"share", b"node", b"(", b"type", b"directory",
+                b"entry", b"(", b"name", b"man", b"node", b"(", b"type", b"directory",
+                    b"entry", b"(", b"name", b"man1", b"node", b"(", b"type", b"directory",
+                        b"entry", b"(", b"name", b"dir.1.gz", b"node", b"(", b"type", b"symlink", b"target", b"ls.1.gz", b")", b")",
+                        b"entry", b"(", b"name", b"ls.1.gz", b"node", b"(", b"type", b"regular", b"contents", b"A man page, honest", b")", b")",
+                    b")", b")",
+                b")", b")",
+            b")", b")",
+        b")"]);
+
+        let mut rd = &data[..];
+        let mut ents = Vec::new();
+        walk(NarEntry::open(&mut rd).unwrap(), |e| {
+            let mut cont = String::new();
+            e.read_to_string(&mut cont).unwrap();
+            ents.push((e.path().unwrap().to_string(), e.filetype(), cont));
+            Ok(true)
+        }).unwrap();
+
+        let d = |p: &str| (p.to_string(), FileType::Directory, String::new());
+        assert_eq!(ents, vec![
+            d("bin"),
+            ("bin/ls".to_string(), FileType::File, "ELF!".to_string()),
+            d("share"),
+            d("share/man"),
+            d("share/man/man1"),
+            ("share/man/man1/dir.1.gz".to_string(), FileType::Link("ls.1.gz".to_string()), String::new()),
+            ("share/man/man1/ls.1.gz".to_string(), FileType::File, "A man page, honest".to_string()),
+        ]);
+        assert!(rd.is_empty());
+
+        let data = nar(&[b"nix-archive-1", b"(", b"type", b"regular", b"contents", b"#!/bin/sh", b")"]);
+        assert!(NarEntry::open(&mut &data[..]).unwrap().is_none());
+
+        let data = nar(&[b"nix-archive-1", b"(", b"type", b"directory", b"entry", b"(", b"name", b"..", b"node"]);
+        assert!(NarEntry::open(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn test_nonutf8() {
+        // "тест" in KOI8-R
+        let data = nar(&[b"nix-archive-1", b"(", b"type", b"directory",
+            b"entry", b"(", b"name", b"man", b"node", b"(", b"type", b"directory",
+                b"entry", b"(", b"name", b"ru", b"node", b"(", b"type", b"directory",
+                    b"entry", b"(", b"name", b"man1", b"node", b"(", b"type", b"directory",
+                        b"entry", b"(", b"name", b"link.1", b"node", b"(", b"type", b"symlink", b"target", b"\xd4\xc5\xd3\xd4.1", b")", b")",
+                        b"entry", b"(", b"name", b"\xd4\xc5\xd3\xd4.1", b"node", b"(", b"type", b"regular", b"contents", b"KOI8-R file name", b")", b")",
+                    b")", b")",
+                b")", b")",
+            b")", b")",
+        b")"]);
+
+        let mut ents = Vec::new();
+        walk(NarEntry::open(&mut &data[..]).unwrap(), |e| {
+            ents.push((e.path().unwrap().to_string(), e.filetype()));
+            Ok(true)
+        }).unwrap();
+        assert_eq!(ents, vec![
+            ("man".to_string(), FileType::Directory),
+            ("man/ru".to_string(), FileType::Directory),
+            ("man/ru/man1".to_string(), FileType::Directory),
+            ("man/ru/man1/link.1".to_string(), FileType::Link("тест.1".to_string())),
+            ("man/ru/man1/тест.1".to_string(), FileType::File),
+        ]);
+    }
+}
@@ -1,15 +1,32 @@
-use std::io::{BufRead,BufReader,Read,Result,Error,ErrorKind,copy};
-use std::fs::{File,create_dir_all,metadata,read_dir,remove_file};
-use std::time::{Duration,SystemTime};
+use std::io::{BufRead,BufReader,Read,Write,Result,Error,ErrorKind,copy,empty,sink};
+use std::fs::{File,create_dir_all,metadata,read_dir,remove_file,rename};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use std::thread::sleep;
+use std::sync::{Mutex,RwLock};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::process;
+use std::collections::{BTreeMap,HashMap};
+use std::ffi::CString;
+use std::ptr;
+use libc;
 use regex::bytes::Regex;
 use ring::digest;
 use url::Url;
 use url::percent_encoding::percent_decode;
+use chrono::NaiveDateTime;
 use hyper;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
 
 
-const CACHE_PATH: &'static str = "/var/tmp/manned-indexer";
+// Files without ETag or Last-Modified header can't be revalidated, these are re-downloaded after
+// CACHE_TIME seconds.
 const CACHE_TIME: u64 = 20*3600;
+const USER_AGENT: &'static str = "Man page crawler (info@manned.org; https://manned.org/)";
+const MAX_REDIRECTS: usize = 5;
+const HTTP_DATE: &'static str = "%a, %d %b %Y %H:%M:%S GMT";
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: u64 = 1000; // ms, doubled after each attempt
 
 
 #[derive(Clone,Copy)]
@@ -20,72 +37,581 @@ pub struct Path<'a> {
 }
 
 
-fn cache_fn(url: &Url) -> String {
-    let name = url.path_segments().unwrap().last().unwrap();
-    let name = if name == "" { "index" } else { name };
+pub struct CacheOpt {
+    pub dir: String,
+    // Maximum total size of the cached files in bytes. Least recently used files are removed
+    // first.
+    pub size: u64,
+    // Whether to cache packages as well, rather than only the repository index files.
+    pub packages: bool,
+}
+
+impl Default for CacheOpt {
+    fn default() -> CacheOpt {
+        CacheOpt {
+            dir: "/var/tmp/manned-indexer".to_string(),
+            size: 10*1024*1024*1024,
+            packages: false,
+        }
+    }
+}
+
+// The cache options with an in-memory index of the data files in the cache directory, so that
+// evicting files doesn't require a scan of the directory after every download.
+struct Cache {
+    opt: CacheOpt,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    total: u64,
+    last: u64,
+    // Data file -> (last use, size)
+    files: HashMap<String, (u64, u64)>,
+    // Last use -> data file, least recently used first
+    order: BTreeMap<u64, String>,
+}
+
+lazy_static!(
+    static ref CACHE: RwLock<Cache> = RwLock::new(Cache { opt: CacheOpt::default(), lru: Mutex::new(Lru::default()) });
+    static ref MIRRORS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+);
+
+
+// Set the list of equivalent mirrors. A URL that starts with one of these mirrors can be fetched
+// from any of the others if that fails.
+pub fn set_mirrors(m: Vec<String>) {
+    *MIRRORS.write().unwrap() = m;
+}
+
+
+// Run f() for the URL on each mirror in the list until one succeeds. The mirror that worked is
+// moved to the front of the list, so that a mirror that is down only slows down a single fetch.
+fn with_mirrors<T, F>(list: &RwLock<Vec<String>>, url: &str, f: F) -> Result<T>
+    where F: Fn(&str) -> Result<T>
+{
+    let found = {
+        let mirrors = list.read().unwrap();
+        mirrors.iter().filter(|m| url.starts_with(&m[..])).max_by_key(|m| m.len()).map(|m| (m.len(), mirrors.clone()))
+    };
+    let (rel, mirrors) = match found {
+        Some((len, l)) => (&url[len..], l),
+        None => return f(url),
+    };
+
+    let mut err = None;
+    for (i, m) in mirrors.iter().enumerate() {
+        let u = format!("{}{}", m, rel);
+        match f(&u) {
+            Ok(x) => {
+                info!("Fetched {} from {}", rel, m);
+                if i > 0 {
+                    let mut l = list.write().unwrap();
+                    l.retain(|x| x != m);
+                    l.insert(0, m.clone());
+                }
+                return Ok(x);
+            },
+            Err(e) => {
+                warn!("Error fetching {}: {}", u, e);
+                err = Some(e);
+            },
+        }
+    }
+    Err(err.unwrap())
+}
+
+
+// A request for a single URL. Redirects are handled by the caller, not by the transport.
+pub struct Request<'a> {
+    pub url: &'a Url,
+    pub head: bool,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Box<Read>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+
+// Header names are case-insensitive.
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|h| h.0.eq_ignore_ascii_case(name)).map(|h| &h.1 as &str)
+}
+
+
+// Something that can fetch URLs. The Std transport is used for all Path operations, tests can
+// use their own transport with Path::open_with() to avoid network access.
+pub trait Transport {
+    fn request(&self, req: &Request) -> Result<Response>;
+}
+
 
-    let hash = digest::digest(&digest::SHA1, url.as_str().as_bytes())
-        .as_ref()[0..8].into_iter()
-        .fold(0u64, |a, &e| (a<<8) + e as u64);
+// http:// and https:// over hyper, file:// from the local filesystem.
+pub struct Std;
 
-    format!("{}/{}-{}-{:x}", CACHE_PATH, url.host_str().unwrap(), name, hash)
+impl Transport for Std {
+    fn request(&self, req: &Request) -> Result<Response> {
+        match req.url.scheme() {
+            "http" | "https" => http_request(req),
+            "file" => file_request(req),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid scheme")),
+        }
+    }
 }
 
 
-fn fetch(url: &str) -> Result<Box<Read>> {
-    let res = try!(hyper::Client::new()
-        .get(url)
-        .header(hyper::header::UserAgent("Man page crawler (info@manned.org; https://manned.org/)".to_owned()))
+fn http_request(req: &Request) -> Result<Response> {
+    let tls = NativeTlsClient::new().map_err(|e| Error::new(ErrorKind::Other, format!("TLS: {}", e)))?;
+    let mut client = hyper::Client::with_connector(HttpsConnector::new(tls));
+    client.set_redirect_policy(hyper::client::RedirectPolicy::FollowNone);
+
+    let mut headers = hyper::header::Headers::new();
+    headers.set(hyper::header::UserAgent(USER_AGENT.to_owned()));
+    for &(k, ref v) in &req.headers {
+        headers.set_raw(k, vec![v.as_bytes().to_vec()]);
+    }
+
+    let method = if req.head { hyper::method::Method::Head } else { hyper::method::Method::Get };
+    let res = try!(client
+        .request(method, req.url.clone())
+        .headers(headers)
         .send()
         .map_err(|e| Error::new(ErrorKind::Other, format!("Hyper: {}", e)))
     );
-    if !res.status.is_success() {
-        return Err(Error::new(ErrorKind::Other, format!("HTTP: {}", res.status) ));
-    }
-    Ok(Box::new(res) as Box<Read>)
+    Ok(Response {
+        status: res.status.to_u16(),
+        headers: res.headers.iter().map(|h| (h.name().to_string(), h.value_string())).collect(),
+        body: Box::new(res) as Box<Read>,
+    })
 }
 
 
-fn file(path: &str) -> Result<Box<Read>> {
-    Ok(Box::new(try!(File::open(path))) as Box<Read>)
+fn file_request(req: &Request) -> Result<Response> {
+    let path = req.url.to_file_path().map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid file URL"))?;
+    let f = File::open(path)?;
+    let mtime = f.metadata()?.modified()?.duration_since(UNIX_EPOCH).ok()
+        .map(|d| NaiveDateTime::from_timestamp(d.as_secs() as i64, 0).format(HTTP_DATE).to_string());
+    Ok(Response {
+        status: 200,
+        headers: mtime.map(|t| ("Last-Modified".to_string(), t)).into_iter().collect(),
+        body: if req.head { Box::new(empty()) as Box<Read> } else { Box::new(f) as Box<Read> },
+    })
 }
 
 
-pub fn clear_cache() -> Result<()> {
-    create_dir_all(CACHE_PATH)?;
-    for f in read_dir(CACHE_PATH)? {
-        let f = f?.path();
-        let m = metadata(&f)?;
-        if m.modified().unwrap() < SystemTime::now() - Duration::from_secs(CACHE_TIME) {
-            remove_file(&f)?;
+fn http_error(status: u16) -> Error {
+    let kind = match status {
+        401 | 403 => ErrorKind::PermissionDenied,
+        404 | 410 => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    };
+    Error::new(kind, format!("HTTP: {}", status))
+}
+
+
+// Whether it makes sense to try again after this error. Network errors and server errors are often
+// temporary, a missing file or an invalid URL isn't.
+fn retryable(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::InvalidInput | ErrorKind::InvalidData => false,
+        _ => true,
+    }
+}
+
+
+// Send a request, following redirects. Returns the final URL and response, or an error if the
+// final response isn't successful. A 304 is only returned for conditional requests, so that is
+// considered successful, too.
+fn send(t: &Transport, url: &str, head: bool, headers: &[(&'static str, String)]) -> Result<(Url, Response)> {
+    let mut url = Url::parse(url).map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid URL"))?;
+    for _ in 0..MAX_REDIRECTS+1 {
+        let res = t.request(&Request { url: &url, head: head, headers: headers.to_vec() })?;
+        match res.status {
+            200..=299 | 304 => return Ok((url, res)),
+            301 | 302 | 303 | 307 | 308 => {
+                let loc = res.header("Location").ok_or_else(|| Error::new(ErrorKind::InvalidData, "HTTP: Redirect without location"))?;
+                url = url.join(loc).map_err(|_| Error::new(ErrorKind::InvalidData, format!("HTTP: Invalid redirect to {}", loc)))?;
+                debug!("Following redirect to {}", url);
+            },
+            s => return Err(http_error(s)),
         }
     }
-    Ok(())
+    Err(Error::new(ErrorKind::InvalidData, "HTTP: Too many redirects"))
 }
 
 
-impl<'a> Path<'a> {
-    pub fn open(&self) -> Result<Box<Read>> {
-        if let Ok(url) = Url::parse(self.path) {
-            if url.scheme() != "http" {
-                return Err(Error::new(ErrorKind::Other, "Invalid scheme"));
+// Reader for a response body that reconnects when the connection fails halfway, and continues at
+// the same offset using a Range request. Failed requests are retried with an exponential backoff,
+// so a flaky mirror only slows things down rather than aborting the entire package.
+struct Resume<'t> {
+    t: &'t Transport,
+    url: Url,
+    // Status and headers of the first response
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Box<Read>,
+    pos: u64,
+    len: Option<u64>,
+    // ETag or Last-Modified of the first response and the name of that header, to make sure we
+    // resume the same file.
+    validator: Option<(&'static str, String)>,
+    delay: Duration,
+}
+
+impl<'t> Resume<'t> {
+    fn open(t: &'t Transport, url: &str, headers: &[(&'static str, String)], delay: Duration) -> Result<Resume<'t>> {
+        let mut tries = 0;
+        let (url, res) = loop {
+            match send(t, url, false, headers) {
+                Ok(x) => break x,
+                Err(ref e) if tries < MAX_RETRIES && retryable(e) => warn!("Error fetching {}: {}, retrying", url, e),
+                Err(e) => return Err(e),
             }
+            sleep(delay * (1 << tries));
+            tries += 1;
+        };
+        Ok(Resume {
+            t: t,
+            len: res.header("Content-Length").and_then(|l| l.parse().ok()),
+            validator: ["ETag", "Last-Modified"].iter().filter_map(|&h| res.header(h).map(|v| (h, v.to_string()))).next(),
+            url: url,
+            status: res.status,
+            headers: res.headers,
+            body: res.body,
+            pos: 0,
+            delay: delay,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
 
-            if self.cache {
-                let cfn = cache_fn(&url);
-                if let Ok(f) = file(&cfn) {
-                    return Ok(f);
+    fn reconnect(&mut self) -> Result<()> {
+        let mut headers = vec![("Range", format!("bytes={}-", self.pos))];
+        if let Some((_, ref v)) = self.validator {
+            headers.push(("If-Range", v.clone()));
+        }
+        let (_, mut res) = send(self.t, self.url.as_str(), false, &headers)?;
+        if res.status == 206 {
+            let start = format!("bytes {}-", self.pos);
+            if !res.header("Content-Range").map(|r| r.starts_with(&start)).unwrap_or(false) {
+                return Err(Error::new(ErrorKind::InvalidData, "HTTP: Unexpected Content-Range"));
+            }
+        } else {
+            // Either the server doesn't support ranges, or the file has changed.
+            if let Some((h, ref old)) = self.validator {
+                if res.header(h).map(|new| new != old).unwrap_or(false) {
+                    return Err(Error::new(ErrorKind::InvalidData, "File has changed while downloading"));
                 }
-                {
-                    let mut rd = try!(fetch(url.as_str()));
-                    let mut wr = try!(File::create(&cfn));
-                    try!(copy(&mut rd, &mut wr));
+            }
+            if copy(&mut (&mut res.body).take(self.pos), &mut sink())? != self.pos {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "HTTP: Unexpected end of file"));
+            }
+        }
+        self.body = res.body;
+        Ok(())
+    }
+}
+
+impl<'t> Read for Resume<'t> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut tries = 0;
+        loop {
+            let err = match self.body.read(buf) {
+                Ok(0) if buf.len() > 0 && self.len.map(|l| self.pos < l).unwrap_or(false) =>
+                    Error::new(ErrorKind::UnexpectedEof, "Connection closed"),
+                Ok(n) => {
+                    self.pos += n as u64;
+                    return Ok(n);
+                },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            if tries >= MAX_RETRIES || !retryable(&err) {
+                return Err(err);
+            }
+            warn!("Error reading {}: {}, resuming at byte {}", self.url, err, self.pos);
+            loop {
+                sleep(self.delay * (1 << tries));
+                tries += 1;
+                match self.reconnect() {
+                    Ok(_) => break,
+                    Err(ref e) if tries < MAX_RETRIES && retryable(e) => warn!("Error resuming {}: {}", self.url, e),
+                    Err(e) => return Err(e),
                 }
-                file(&cfn)
+            }
+        }
+    }
+}
+
+
+fn fetch<'t>(t: &'t Transport, url: &str) -> Result<Box<Read + 't>> {
+    Ok(Box::new(Resume::open(t, url, &[], Duration::from_millis(RETRY_DELAY))?))
+}
+
+
+// Returns the Last-Modified header of the URL, using a HEAD request.
+fn fetch_mtime(t: &Transport, url: &str) -> Result<Option<i64>> {
+    Ok(send(t, url, true, &[])?.1.header("Last-Modified")
+        .and_then(|d| NaiveDateTime::parse_from_str(d, HTTP_DATE).ok())
+        .map(|d| d.timestamp()))
+}
+
+
+fn file<'t>(path: &str) -> Result<Box<Read + 't>> {
+    Ok(Box::new(try!(File::open(path))) as Box<Read>)
+}
+
+
+fn hash(s: &str) -> String {
+    digest::digest(&digest::SHA1, s.as_bytes()).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+// A unique name for a temporary file next to 'path'. Several threads (or processes) may be
+// downloading the same URL into the cache at the same time.
+fn tmpname(path: &str) -> String {
+    static TMP_ID: AtomicUsize = AtomicUsize::new(0);
+    format!("{}.{}-{}.tmp", path, process::id(), TMP_ID.fetch_add(1, Ordering::SeqCst))
+}
+
+
+// Remove a file from the cache, unless someone else got to it first.
+fn remove_cached(path: &str) -> Result<()> {
+    match remove_file(path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+
+// Mark a cached file as recently used.
+fn touch(path: &str) {
+    if let Ok(p) = CString::new(path) {
+        unsafe { libc::utime(p.as_ptr(), ptr::null()) };
+    }
+}
+
+
+/* The cache has two files for each URL:
+ *
+ *   <sha1(url)>.url     The name of the data file on the first line, followed by the ETag and
+ *                       Last-Modified headers of the cached response.
+ *   <sha1(url+headers)> The data file.
+ *
+ * So a data file is identified by the URL and the version of the file on the server, and a
+ * changed file on the server never ends up being confused with an older version.
+ */
+struct CacheRef {
+    data: String,
+    etag: Option<String>,
+    modified: Option<String>,
+}
+
+
+fn read_ref(opt: &CacheOpt, reffn: &str) -> Option<CacheRef> {
+    let mut lines = BufReader::new(File::open(reffn).ok()?).lines().filter_map(|l| l.ok());
+    let data = format!("{}/{}", opt.dir, lines.next()?);
+    if metadata(&data).is_err() {
+        return None;
+    }
+    let mut r = CacheRef { data: data, etag: None, modified: None };
+    for l in lines {
+        let mut kv = l.splitn(2, ": ");
+        match (kv.next(), kv.next()) {
+            (Some("ETag"), Some(v)) => r.etag = Some(v.to_string()),
+            (Some("Last-Modified"), Some(v)) => r.modified = Some(v.to_string()),
+            _ => (),
+        }
+    }
+    Some(r)
+}
+
+
+fn cached_fetch<'t>(t: &'t Transport, cache: &Cache, url: &str) -> Result<Box<Read + 't>> {
+    let opt = &cache.opt;
+    let reffn = format!("{}/{}.url", opt.dir, hash(url));
+    let old = read_ref(opt, &reffn);
 
+    let mut headers = Vec::new();
+    if let Some(ref o) = old {
+        if let Some(ref v) = o.etag {
+            headers.push(("If-None-Match", v.clone()));
+        }
+        if let Some(ref v) = o.modified {
+            headers.push(("If-Modified-Since", v.clone()));
+        }
+        let fresh = metadata(&reffn)?.modified()? > SystemTime::now() - Duration::from_secs(CACHE_TIME);
+        if headers.is_empty() && fresh {
+            touch(&o.data);
+            cache.lru.lock().unwrap().used(&o.data);
+            return file(&o.data);
+        }
+    }
+
+    let mut rd = Resume::open(t, url, &headers, Duration::from_millis(RETRY_DELAY))?;
+    if let (304, Some(o)) = (rd.status, old.as_ref()) {
+        debug!("Using cached {}", url);
+        touch(&o.data);
+        cache.lru.lock().unwrap().used(&o.data);
+        return file(&o.data);
+    }
+
+    let new = CacheRef {
+        etag: rd.header("ETag").map(str::to_string),
+        modified: rd.header("Last-Modified").map(str::to_string),
+        data: String::new(),
+    };
+    let key = hash(&format!("{}\n{}\n{}", url, new.etag.as_ref().map(|x| &x[..]).unwrap_or(""), new.modified.as_ref().map(|x| &x[..]).unwrap_or("")));
+    let datafn = format!("{}/{}", opt.dir, key);
+    {
+        let tmp = tmpname(&datafn);
+        let mut wr = File::create(&tmp)?;
+        let len = copy(&mut rd, &mut wr)?;
+        rename(&tmp, &datafn)?;
+        cache.lru.lock().unwrap().add(&datafn, len);
+
+        let tmp = tmpname(&reffn);
+        let mut wr = File::create(&tmp)?;
+        write!(wr, "{}\n", key)?;
+        if let Some(ref v) = new.etag {
+            write!(wr, "ETag: {}\n", v)?;
+        }
+        if let Some(ref v) = new.modified {
+            write!(wr, "Last-Modified: {}\n", v)?;
+        }
+        rename(&tmp, &reffn)?;
+    }
+    if let Some(o) = old {
+        if o.data != datafn {
+            cache.lru.lock().unwrap().remove(&o.data);
+            remove_cached(&o.data)?;
+        }
+    }
+
+    // Open the file before evicting, so that it can still be read if it's larger than the cache.
+    let f = file(&datafn)?;
+    cache.evict()?;
+    Ok(f)
+}
+
+
+impl Lru {
+    // Add a data file or mark it as the most recently used.
+    fn add(&mut self, path: &str, len: u64) {
+        self.remove(path);
+        self.last += 1;
+        self.total += len;
+        self.files.insert(path.to_string(), (self.last, len));
+        self.order.insert(self.last, path.to_string());
+    }
+
+    fn used(&mut self, path: &str) {
+        if let Some(&(_, len)) = self.files.get(path) {
+            self.add(path, len);
+        }
+    }
+
+    fn remove(&mut self, path: &str) {
+        if let Some((last, len)) = self.files.remove(path) {
+            self.order.remove(&last);
+            self.total -= len;
+        }
+    }
+
+    // Returns the least recently used files that have to go for the cache to fit within 'size'.
+    fn evict(&mut self, size: u64) -> Vec<String> {
+        let mut res = Vec::new();
+        while self.total > size {
+            let path = match self.order.values().next() { Some(x) => x.clone(), None => break };
+            self.remove(&path);
+            res.push(path);
+        }
+        res
+    }
+}
+
+
+impl Cache {
+    // Clean up the cache directory and index the data files in it.
+    fn load(opt: CacheOpt) -> Result<Cache> {
+        create_dir_all(&opt.dir)?;
+        let mut files = Vec::new();
+        for f in read_dir(&opt.dir)? {
+            let f = f?;
+            let path = f.path();
+            let name = match path.to_str() { Some(x) => x, None => continue };
+            // Leftovers from an interrupted download
+            if name.ends_with(".tmp") {
+                remove_file(&path)?;
+            } else if name.ends_with(".url") {
+                if read_ref(&opt, name).is_none() {
+                    remove_file(&path)?;
+                }
             } else {
-                fetch(url.as_str())
+                let m = f.metadata()?;
+                files.push((m.modified()?, m.len(), name.to_string()));
             }
+        }
+
+        files.sort();
+        let mut lru = Lru::default();
+        for (_, len, path) in files {
+            lru.add(&path, len);
+        }
+        let cache = Cache { opt: opt, lru: Mutex::new(lru) };
+        cache.evict()?;
+        Ok(cache)
+    }
+
+    // Remove the least recently used data files until the cache fits within opt.size. References
+    // to removed files are cleaned up by load().
+    fn evict(&self) -> Result<()> {
+        let files = self.lru.lock().unwrap().evict(self.opt.size);
+        for path in files {
+            debug!("Removing {} from the cache", path);
+            remove_cached(&path)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Set the cache options and clean up the cache directory.
+pub fn init_cache(opt: CacheOpt) -> Result<()> {
+    *CACHE.write().unwrap() = Cache::load(opt)?;
+    Ok(())
+}
+
+
+impl<'a> Path<'a> {
+    pub fn open(&self) -> Result<Box<Read>> {
+        self.open_with(&Std)
+    }
+
+    pub fn open_with<'t>(&self, t: &'t Transport) -> Result<Box<Read + 't>> {
+        if Url::parse(self.path).is_ok() {
+            with_mirrors(&MIRRORS, self.path, |url| {
+                let cache = CACHE.read().unwrap();
+                // Caching local files is pointless
+                if (self.cache || cache.opt.packages) && !url.starts_with("file:") {
+                    cached_fetch(t, &cache, url)
+                } else {
+                    fetch(t, url)
+                }
+            })
 
         } else if self.canbelocal {
             file(self.path)
@@ -95,6 +621,20 @@ impl<'a> Path<'a> {
         }
     }
 
+    // Returns the last modification time of the file as a UNIX timestamp, if known.
+    pub fn mtime(&self) -> Result<Option<i64>> {
+        if Url::parse(self.path).is_ok() {
+            with_mirrors(&MIRRORS, self.path, |url| fetch_mtime(&Std, url))
+
+        } else if self.canbelocal {
+            let t = metadata(self.path)?.modified()?;
+            Ok(t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs() as i64))
+
+        } else {
+            Err(Error::new(ErrorKind::Other, "Invalid URL"))
+        }
+    }
+
     // Attempt to parse a HTTP directory listing. Returns the name and whether it's a directory for
     // each item.
     // Only tested with a lighttpd/1.4 and apache 2.4 server.
@@ -132,3 +672,222 @@ impl<'a> Path<'a> {
     }
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::cell::Cell;
+    use std::io::Cursor;
+    use std::fs::remove_dir_all;
+    use std::{env,process};
+
+    // In-memory transport, maps a URL to the status, headers and body of the response.
+    struct Mem(HashMap<&'static str, (u16, Vec<(&'static str, &'static str)>, &'static [u8])>);
+
+    impl Transport for Mem {
+        fn request(&self, req: &Request) -> Result<Response> {
+            match self.0.get(req.url.as_str()) {
+                None => Ok(Response { status: 404, headers: Vec::new(), body: Box::new(empty()) }),
+                Some(&(status, ref headers, body)) => Ok(Response {
+                    status: status,
+                    headers: headers.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
+                    body: Box::new(if req.head { &b""[..] } else { body }),
+                }),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut m = HashMap::new();
+        m.insert("http://a/file", (200, vec![("Last-Modified", "Sat, 04 Aug 2018 10:11:12 GMT")], &b"contents"[..]));
+        m.insert("http://a/moved", (301, vec![("location", "/file")], &b""[..]));
+        m.insert("http://b/loop", (302, vec![("Location", "http://b/loop")], &b""[..]));
+        let t = Mem(m);
+
+        let mut s = String::new();
+        Path{ path: "http://a/moved", cache: false, canbelocal: false }.open_with(&t).unwrap().read_to_string(&mut s).unwrap();
+        assert_eq!(s, "contents");
+        assert_eq!(fetch_mtime(&t, "http://a/moved").unwrap(), Some(1533377472));
+        assert!(fetch(&t, "http://a/missing").is_err());
+        assert!(fetch(&t, "http://b/loop").is_err());
+        assert!(fetch(&Std, "ftp://a/file").is_err());
+
+        let url = format!("file://{}/tests/simpletest.tar.gz", env!("CARGO_MANIFEST_DIR"));
+        assert!(fetch_mtime(&Std, &url).unwrap().is_some());
+        assert!(fetch(&Std, &url).unwrap().read_to_end(&mut Vec::new()).unwrap() > 0);
+    }
+
+    // Serves 'data', but drops the connection after 'chunk' bytes on the first request, twice that
+    // on the second request, etc.
+    struct Flaky {
+        data: Vec<u8>,
+        chunk: usize,
+        ranges: bool,
+        requests: Cell<usize>,
+    }
+
+    struct Dropped(Cursor<Vec<u8>>);
+
+    impl Read for Dropped {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(Error::new(ErrorKind::ConnectionReset, "Connection reset by peer")),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Transport for Flaky {
+        fn request(&self, req: &Request) -> Result<Response> {
+            self.requests.set(self.requests.get() + 1);
+            let range = req.headers.iter().find(|h| h.0 == "Range").filter(|_| self.ranges);
+            let start: usize = range.map(|r| r.1.trim_left_matches("bytes=").trim_right_matches('-').parse().unwrap()).unwrap_or(0);
+            let end = start + self.chunk * self.requests.get();
+            let end = if end < self.data.len() { end } else { self.data.len() };
+            let body = Cursor::new(self.data[start..end].to_vec());
+            let mut headers = vec![
+                ("ETag".to_string(), "\"abc\"".to_string()),
+                ("Content-Length".to_string(), format!("{}", self.data.len() - start)),
+            ];
+            if range.is_some() {
+                headers.push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, self.data.len()-1, self.data.len())));
+            }
+            Ok(Response {
+                status: if range.is_some() { 206 } else { 200 },
+                headers: headers,
+                body: if end == self.data.len() { Box::new(body) } else { Box::new(Dropped(body)) },
+            })
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let data: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let read = |t: &Flaky| {
+            let mut buf = Vec::new();
+            Resume::open(t, "http://a/file", &[], Duration::from_millis(0))?.read_to_end(&mut buf).map(|_| buf)
+        };
+
+        let t = Flaky { data: data.clone(), chunk: 3000, ranges: true, requests: Cell::new(0) };
+        assert_eq!(read(&t).unwrap(), data);
+        assert_eq!(t.requests.get(), 3);
+
+        let t = Flaky { data: data.clone(), chunk: 3000, ranges: false, requests: Cell::new(0) };
+        assert_eq!(read(&t).unwrap(), data);
+        assert_eq!(t.requests.get(), 4);
+
+        // Connection drops before sending anything
+        let t = Flaky { data: data.clone(), chunk: 0, ranges: true, requests: Cell::new(0) };
+        assert!(read(&t).is_err());
+        assert_eq!(t.requests.get(), 1 + MAX_RETRIES as usize);
+    }
+
+    // Serves a single file with an ETag and optionally a Last-Modified header, supports conditional
+    // requests.
+    struct Versioned {
+        etag: Cell<&'static str>,
+        modified: Option<&'static str>,
+        body: Cell<&'static [u8]>,
+        requests: Cell<usize>,
+    }
+
+    impl Transport for Versioned {
+        fn request(&self, req: &Request) -> Result<Response> {
+            self.requests.set(self.requests.get() + 1);
+            if req.headers.iter().any(|h| h.0 == "If-None-Match" && h.1 == self.etag.get()) {
+                return Ok(Response { status: 304, headers: Vec::new(), body: Box::new(empty()) });
+            }
+            let mut headers: Vec<_> = self.modified.map(|m| ("Last-Modified".to_string(), m.to_string())).into_iter().collect();
+            headers.push(("ETag".to_string(), self.etag.get().to_string()));
+            Ok(Response {
+                status: 200,
+                headers: headers,
+                body: Box::new(self.body.get()),
+            })
+        }
+    }
+
+    #[test]
+    fn test_resume_validator() {
+        // The ETag is used as validator, the Last-Modified header that comes before it is ignored
+        let t = Versioned { etag: Cell::new("\"1\""), modified: Some("Sat, 04 Aug 2018 10:11:12 GMT"), body: Cell::new(b"version 1"), requests: Cell::new(0) };
+        let mut rd = Resume::open(&t, "http://a/file", &[], Duration::from_millis(0)).unwrap();
+        let mut buf = [0u8; 8];
+        rd.read_exact(&mut buf).unwrap();
+        rd.reconnect().unwrap();
+        let mut s = String::new();
+        rd.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "1");
+
+        t.etag.set("\"2\"");
+        assert_eq!(rd.reconnect().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(t.requests.get(), 3);
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = env::temp_dir().join(format!("manned-indexer-test-{}", process::id()));
+        let cache = Cache::load(CacheOpt { dir: dir.to_str().unwrap().to_string(), size: 20, packages: false }).unwrap();
+        let datafiles = || read_dir(&cache.opt.dir).unwrap().filter(|f| !f.as_ref().unwrap().file_name().to_str().unwrap().contains('.')).count();
+
+        let t = Versioned { etag: Cell::new("\"1\""), modified: None, body: Cell::new(b"version 1"), requests: Cell::new(0) };
+        let get = |url| {
+            let mut s = String::new();
+            cached_fetch(&t, &cache, url).unwrap().read_to_string(&mut s).unwrap();
+            s
+        };
+        assert_eq!(get("http://a/file"), "version 1");
+        t.body.set(b"modified without changing the ETag");
+        assert_eq!(get("http://a/file"), "version 1");
+        assert_eq!(t.requests.get(), 2);
+
+        t.etag.set("\"2\"");
+        t.body.set(b"version 2");
+        assert_eq!(get("http://a/file"), "version 2");
+        assert_eq!(datafiles(), 1);
+
+        // Concurrent downloads of the same URL don't share a temporary file, and leftovers are
+        // recognized as such by Cache::load()
+        let (a, b) = (tmpname("x"), tmpname("x"));
+        assert!(a != b && a.ends_with(".tmp") && b.ends_with(".tmp"));
+
+        // Only two files fit in the cache
+        assert_eq!(get("http://a/other"), "version 2");
+        assert_eq!(datafiles(), 2);
+        // Revalidating marks it as recently used, so "other" is evicted instead
+        assert_eq!(get("http://a/file"), "version 2");
+        assert_eq!(get("http://a/third"), "version 2");
+        assert_eq!(datafiles(), 2);
+        let cached = |url| read_ref(&cache.opt, &format!("{}/{}.url", cache.opt.dir, hash(url))).is_some();
+        assert!(cached("http://a/file"));
+        assert!(!cached("http://a/other"));
+        assert!(cached("http://a/third"));
+
+        remove_dir_all(&cache.opt.dir).unwrap();
+    }
+
+    #[test]
+    fn test_mirrors() {
+        let mut m = HashMap::new();
+        m.insert("http://m2/pub/file", (200, vec![], &b"m2"[..]));
+        m.insert("http://m3/file", (200, vec![], &b"m3"[..]));
+        m.insert("http://m3/other", (200, vec![], &b"m3"[..]));
+        let t = Mem(m);
+        let list = RwLock::new(vec!["http://m1/".to_string(), "http://m2/pub/".to_string(), "http://m3/".to_string()]);
+        let get = |p: &str| -> Result<String> {
+            let mut s = String::new();
+            with_mirrors(&list, p, |url| fetch(&t, url))?.read_to_string(&mut s)?;
+            Ok(s)
+        };
+
+        assert_eq!(get("http://m1/file").unwrap(), "m2");
+        assert_eq!(list.read().unwrap()[0], "http://m2/pub/");
+        assert_eq!(get("http://m1/other").unwrap(), "m3");
+        assert_eq!(get("http://m2/pub/file").unwrap(), "m3");
+        assert!(get("http://m1/missing").is_err());
+        assert!(get("http://elsewhere/file").is_err());
+    }
+}
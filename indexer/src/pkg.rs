@@ -1,15 +1,27 @@
 use std;
 use std::io::{Error,ErrorKind,Read};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::sync::mpsc::{SyncSender,sync_channel};
+use std::thread;
+use std::panic::{self,AssertUnwindSafe};
 use postgres;
 use chrono::NaiveDateTime;
+use ring::digest;
 
 use open;
 use archread;
 use man;
-use archive::{Format,Archive,ArchiveEntry};
+use archive::{Format,Archive,ArchiveEntry,Entry};
 
 pub static mut DRY_RUN: bool = false;
 
+// Maximum size of the files to spill to disk while indexing a package, 0 to disable. See
+// archread::FileList::spill().
+pub static SPILL_SIZE: AtomicUsize = AtomicUsize::new(0);
+
 
 #[derive(Debug,Clone,Copy)]
 pub enum Date<'a> {
@@ -23,7 +35,7 @@ pub enum Date<'a> {
 
 
 impl<'a> Date<'a> {
-    fn update(&mut self, ent: &ArchiveEntry) {
+    pub fn update<E: Entry>(&mut self, ent: &E) {
         // TODO: Validate that the mtime() date is sensible (e.g. 1990 < date < now)
         *self = match *self {
             Date::Deb if ent.format() == Format::Ar && ent.path() == Some("debian-binary") => Date::Found(ent.mtime()),
@@ -44,10 +56,59 @@ pub struct PkgOpt<'a> {
     pub ver: &'a str,
     pub date: Date<'a>,
     pub arch: Option<&'a str>,
+    pub digest: Option<Checksum<'a>>,
     pub file: open::Path<'a>
 }
 
 
+// Expected checksum of the package file, as listed in the repository metadata. The package is
+// rejected if the file doesn't match.
+#[derive(Clone,Copy)]
+pub struct Checksum<'a> {
+    pub alg: &'static digest::Algorithm,
+    pub hex: &'a str,
+}
+
+impl<'a> Checksum<'a> {
+    // Algorithm names as used in repository metadata. Returns None for unsupported algorithms
+    // (e.g. MD5), packages with such a checksum are indexed without verification.
+    pub fn new(alg: &str, hex: &'a str) -> Option<Checksum<'a>> {
+        let alg = match &alg.to_lowercase() as &str {
+            "sha" | "sha1" => &digest::SHA1,
+            "sha256" => &digest::SHA256,
+            "sha384" => &digest::SHA384,
+            "sha512" => &digest::SHA512,
+            _ => return None,
+        };
+        Some(Checksum { alg: alg, hex: hex })
+    }
+}
+
+
+struct HashRead<'a> {
+    rd: &'a mut Read,
+    ctx: digest::Context,
+}
+
+impl<'a> Read for HashRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.rd.read(buf)?;
+        self.ctx.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+
+// Whether a version of a package is already in the database, in any category. Lets backends skip
+// fetching metadata for packages that pkg() would skip anyway.
+pub fn exists(pg: &postgres::GenericConnection, sys: i32, name: &str, ver: &str) -> bool {
+    !pg.query(
+        "SELECT 1 FROM packages p JOIN package_versions v ON v.package = p.id WHERE p.system = $1 AND p.name = $2 AND v.version = $3",
+        &[&sys, &name, &ver]
+    ).unwrap().is_empty()
+}
+
+
 fn insert_pkg(tr: &postgres::transaction::Transaction, opt: &PkgOpt) -> Option<i32> {
     let pkginfo = format!("sys {} / {} / {} - {} @ {:?} @ {}", opt.sys, opt.cat, opt.pkg, opt.ver, opt.date, opt.file.path);
 
@@ -107,7 +168,104 @@ fn insert_man_row(tr: &postgres::GenericConnection, verid: i32, path: &str, enc:
 }
 
 
-fn insert_man(tr: &postgres::GenericConnection, verid: i32, paths: &[&str], ent: &mut Read) {
+// Overwrite entry if the contents are different. It's possible that earlier decoding
+// implementations didn't properly detect the encoding. (On the other hand, due to differences
+// in filenames it's also possible that THIS decoding step went wrong, but that's slightly less
+// likely)
+// This is not a single ON CONFLICT DO UPDATE because that locks the existing row until the end of
+// the transaction even if nothing changes, and concurrent workers inserting pages shared between
+// packages in a different order would then deadlock on each other.
+fn insert_contents(tr: &postgres::GenericConnection, hash: &[u8], cont: &str) {
+    let n = tr.execute(
+        "INSERT INTO contents (hash, content) VALUES($1, $2) ON CONFLICT (hash) DO NOTHING",
+        &[&hash, &cont]
+    ).unwrap();
+    if n == 0 {
+        tr.execute("UPDATE contents SET content = $2 WHERE hash = $1 AND content <> $2", &[&hash, &cont]).unwrap();
+    }
+}
+
+
+// A man page that only consists of a ".so" request. These are kept aside until the entire package
+// has been read, so that they can be inserted as a link to the included page instead.
+#[derive(Clone)]
+struct Stub {
+    path: String,
+    // Path of the stub page itself, the included path is relative to this. Differs from 'path'
+    // when that is a link to the stub.
+    base: String,
+    target: String,
+    hash: Vec<u8>,
+    enc: &'static str,
+    cont: String,
+}
+
+// The stubs found so far in the package that is being indexed. Created by pkg_with(), passed to
+// insert_man(), insert_link() and delete_man() and inserted after the package has been read.
+#[derive(Default)]
+pub struct Stubs(RefCell<Vec<Stub>>);
+
+
+// Follow the ".so" requests of each stub until a regular man page in 'pages' is found, with a
+// limit in case of loops. 'pages' is indexed by path without compression extensions. Returns the
+// path of the included page for each stub, or None if it can't be resolved.
+fn resolve_stubs(pages: &HashMap<String, (Vec<u8>, String)>, stubs: &[Stub]) -> Vec<Option<String>> {
+    let bypath: HashMap<&str, &Stub> = stubs.iter().map(|s| (man::strip_compression(&s.path), s)).collect();
+
+    let resolve = |s: &Stub| {
+        let mut s = s;
+        for _ in 0..8 {
+            let mut next = None;
+            for p in man::so_paths(&s.base, &s.target) {
+                if pages.contains_key(&p) {
+                    return Some(p);
+                }
+                if let Some(&n) = bypath.get(&p[..]) {
+                    next = Some(n);
+                    break;
+                }
+            }
+            s = match next { Some(n) => n, None => return None };
+        }
+        None
+    };
+    stubs.iter().map(resolve).collect()
+}
+
+
+// Insert the stubs of a package. Stubs that include another page in the same package are inserted
+// as a link to that page, others are inserted as a regular man page.
+fn insert_stubs(tr: &postgres::GenericConnection, verid: i32, stubs: Stubs) {
+    let stubs = stubs.0.into_inner();
+    if stubs.is_empty() {
+        return;
+    }
+    let mut pages = HashMap::new();
+    for row in &tr.query("SELECT filename, hash, encoding FROM man WHERE package = $1", &[&verid]).unwrap() {
+        let filename: String = row.get(0);
+        let hash: Vec<u8> = row.get(1);
+        let enc: String = row.get(2);
+        pages.insert(man::strip_compression(filename.trim_left_matches('/')).to_string(), (hash, enc));
+    }
+
+    for (s, dest) in stubs.iter().zip(resolve_stubs(&pages, &stubs)) {
+        match dest {
+            Some(dest) => {
+                let (ref hash, ref enc) = pages[&dest];
+                insert_man_row(tr, verid, &s.path, enc, hash);
+                info!("Inserted .so link: {} -> {}", s.path, dest);
+            },
+            None => {
+                insert_contents(tr, &s.hash, &s.cont);
+                insert_man_row(tr, verid, &s.path, s.enc, &s.hash);
+                info!("Inserted man page: {} ({}, unresolved .so {})", s.path, s.enc, s.target);
+            },
+        }
+    }
+}
+
+
+pub fn insert_man(tr: &postgres::GenericConnection, verid: i32, stubs: &Stubs, paths: &[&str], ent: &mut Read) {
     let (dig, enc, mut cont) = match man::decode(paths, ent) {
         Err(e) => { error!("Error decoding {}: {}", paths[0], e); return },
         Ok(x) => x,
@@ -119,14 +277,23 @@ fn insert_man(tr: &postgres::GenericConnection, verid: i32, paths: &[&str], ent:
         cont = cont.replace(0 as char, "");
     }
 
-    // Overwrite entry if the contents are different. It's possible that earlier decoding
-    // implementations didn't properly detect the encoding. (On the other hand, due to differences
-    // in filenames it's also possible that THIS decoding step went wrong, but that's slightly less
-    // likely)
-    tr.execute(
-        "INSERT INTO contents (hash, content) VALUES($1, $2) ON CONFLICT (hash) DO UPDATE SET content = $2",
-        &[&dig.as_ref(), &cont]
-    ).unwrap();
+    if let Some(target) = man::so_target(&cont) {
+        let mut list = stubs.0.borrow_mut();
+        for path in paths {
+            debug!("Found .so stub: {} -> {}", path, target);
+            list.push(Stub {
+                path: path.to_string(),
+                base: path.to_string(),
+                target: target.to_string(),
+                hash: dig.as_ref().to_vec(),
+                enc: enc,
+                cont: cont.clone(),
+            });
+        }
+        return;
+    }
+
+    insert_contents(tr, dig.as_ref(), &cont);
 
     for path in paths {
         insert_man_row(tr, verid, path, enc, dig.as_ref());
@@ -135,7 +302,26 @@ fn insert_man(tr: &postgres::GenericConnection, verid: i32, paths: &[&str], ent:
 }
 
 
-fn insert_link(tr: &postgres::GenericConnection, verid: i32, src: &str, dest: &str) {
+// Remove a man page that has been inserted earlier, when it turns out that a later entry in the
+// archive replaces it.
+pub fn delete_man(tr: &postgres::GenericConnection, verid: i32, stubs: &Stubs, path: &str) {
+    stubs.0.borrow_mut().retain(|s| s.path != path);
+    tr.execute("DELETE FROM man WHERE package = $1 AND filename = '/'||$2", &[&verid, &path]).unwrap();
+    info!("Removed replaced man page: {}", path);
+}
+
+
+pub fn insert_link(tr: &postgres::GenericConnection, verid: i32, stubs: &Stubs, src: &str, dest: &str) {
+    {
+        let mut list = stubs.0.borrow_mut();
+        let stub = list.iter().find(|s| s.path == dest).map(|s| Stub { path: src.to_string(), ..s.clone() });
+        if let Some(s) = stub {
+            debug!("Found link to .so stub: {} -> {}", src, dest);
+            list.push(s);
+            return;
+        }
+    }
+
     let res = tr.query("SELECT hash, encoding FROM man WHERE package = $1 AND filename = '/'||$2", &[&verid, &dest]).unwrap();
     if res.is_empty() { /* Can happen if man::decode() failed previously. */
         error!("Link to unindexed man page: {} -> {}", src, dest);
@@ -148,11 +334,47 @@ fn insert_link(tr: &postgres::GenericConnection, verid: i32, src: &str, dest: &s
 }
 
 
-fn with_pkg<F,T>(opt: &mut PkgOpt, cb: F) -> std::io::Result<T>
+// Open the package at opt.file. If inner is set, opt.file is an archive (e.g. an ISO image) and the
+// package is read from the inner path in that archive.
+fn with_pkg<F,T>(opt: &mut PkgOpt, inner: Option<&str>, cb: F) -> std::io::Result<T>
     where F: FnOnce(Option<ArchiveEntry>, &mut PkgOpt) -> std::io::Result<T>
 {
     let mut rd = opt.file.open()?;
-    let ent = match Archive::open_archive(&mut rd)? {
+    let inner = match inner { Some(x) => x, None => return with_pkg_rd(&mut rd, opt, cb) };
+
+    let mut ent = Archive::open_archive(&mut rd)?;
+    while let Some(mut e) = ent {
+        if e.path() == Some(inner) {
+            return with_pkg_rd(&mut e, opt, cb);
+        }
+        ent = e.next()?
+    }
+    Err(Error::new(ErrorKind::NotFound, format!("Package not found in archive: {}", inner)))
+}
+
+
+// Same as read_pkg(), but also verifies opt.digest while reading.
+fn with_pkg_rd<F,T>(rd: &mut Read, opt: &mut PkgOpt, cb: F) -> std::io::Result<T>
+    where F: FnOnce(Option<ArchiveEntry>, &mut PkgOpt) -> std::io::Result<T>
+{
+    let sum = match opt.digest { Some(x) => x, None => return read_pkg(rd, opt, cb) };
+    let mut hr = HashRead { rd: rd, ctx: digest::Context::new(sum.alg) };
+    let res = read_pkg(&mut hr, opt, cb)?;
+
+    // The callback doesn't necessarily read the entire file.
+    std::io::copy(&mut hr, &mut std::io::sink())?;
+    let hex: String = hr.ctx.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    if !hex.eq_ignore_ascii_case(sum.hex) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Checksum mismatch for {}: expected {}, got {}", opt.file.path, sum.hex, hex)));
+    }
+    Ok(res)
+}
+
+
+fn read_pkg<F,T>(rd: &mut Read, opt: &mut PkgOpt, cb: F) -> std::io::Result<T>
+    where F: FnOnce(Option<ArchiveEntry>, &mut PkgOpt) -> std::io::Result<T>
+{
+    let ent = match Archive::open_archive(rd)? {
         None => return cb(None, opt),
         Some(x) => x,
     };
@@ -170,6 +392,30 @@ fn with_pkg<F,T>(opt: &mut PkgOpt, cb: F) -> std::io::Result<T>
         }
         Err(Error::new(ErrorKind::Other, "Debian file without data.tar"))
 
+    // Gentoo .gpkg.tar, the files are in <name>/image.tar.*
+    } else if ent.format() == Format::Tar && ent.path().map(|p| p == "gpkg-1" || p.ends_with("/gpkg-1")) == Some(true) {
+        opt.date.update(&ent);
+        let mut ent = ent.next()?;
+        while let Some(mut e) = ent {
+            opt.date.update(&e);
+            if e.path().and_then(|p| p.rsplit('/').next()).map(|p| p.starts_with("image.tar") && !p.ends_with(".sig")) == Some(true) {
+                return cb(Archive::open_archive(&mut e)?, opt);
+            }
+            ent = e.next()?
+        }
+        Err(Error::new(ErrorKind::Other, "GPKG file without image.tar"))
+
+    // Conda .conda, a zip file with the files in pkg-<name>.tar.zst
+    } else if ent.format() == Format::Zip && ent.path() == Some("metadata.json") {
+        let mut ent = ent.next()?;
+        while let Some(mut e) = ent {
+            if e.path().map(|p| p.starts_with("pkg-") && p.ends_with(".tar.zst")) == Some(true) {
+                return cb(Archive::open_archive(&mut e)?, opt);
+            }
+            ent = e.next()?
+        }
+        Err(Error::new(ErrorKind::Other, "Conda file without pkg tarball"))
+
     // any other archive (Arch/FreeBSD .tar)
     } else {
         cb(Some(ent), opt)
@@ -177,21 +423,51 @@ fn with_pkg<F,T>(opt: &mut PkgOpt, cb: F) -> std::io::Result<T>
 }
 
 
-fn index_pkg(tr: &postgres::GenericConnection, mut opt: PkgOpt, verid: i32) -> std::io::Result<()> {
+// inner: See with_pkg(). The reader is used for the first read of the package, so that packages
+// can be read while walking through the outer archive.
+fn index_pkg(tr: &postgres::GenericConnection, opt: &mut PkgOpt, verid: i32, stubs: &Stubs, inner: Option<(&str, &mut Read)>) -> std::io::Result<()> {
     let indexfunc = |paths: &[&str], ent: &mut ArchiveEntry| {
-        insert_man(tr, verid, paths, ent);
+        insert_man(tr, verid, stubs, paths, ent);
         Ok(()) /* Don't propagate errors, continue handling other man pages */
     };
 
-    let missed = with_pkg(&mut opt, |e, opt| {
-            archread::FileList::read(e, man::ismanpath, |ent| opt.date.update(ent), &indexfunc)
-        })?.links(|src, dest| { insert_link(tr, verid, src, dest) });
+    let filelist = || -> std::io::Result<archread::FileList> {
+        let mut fl = archread::FileList::new();
+        let spill = SPILL_SIZE.load(Ordering::Relaxed);
+        if spill > 0 {
+            fl.spill(spill)?;
+        }
+        Ok(fl)
+    };
+
+    let (inner, rd) = match inner { Some((p, rd)) => (Some(p), Some(rd)), None => (None, None) };
+    let missed = match rd {
+        None => with_pkg(opt, None, |e, opt| {
+            let mut fl = filelist()?;
+            fl.add(e, |_| true, man::ismanpath, |ent| opt.date.update(ent), &indexfunc, |path| delete_man(tr, verid, stubs, path))?;
+            Ok(fl)
+        }),
+        Some(rd) => with_pkg_rd(rd, opt, |e, opt| {
+            let mut fl = filelist()?;
+            fl.add(e, |_| true, man::ismanpath, |ent| opt.date.update(ent), &indexfunc, |path| delete_man(tr, verid, stubs, path))?;
+            Ok(fl)
+        }),
+    }?.links(|src, dest| { insert_link(tr, verid, stubs, src, dest) });
+
+    let missed = match missed {
+        Some(m) => m.read_spilled(|paths, f| { insert_man(tr, verid, stubs, paths, f); Ok(()) })?,
+        None => None,
+    };
 
     if let Some(missed) = missed {
         warn!("Some links were missed, reading package again");
-        with_pkg(&mut opt, |e, _| { missed.read(e, indexfunc) })?
+        with_pkg(opt, inner, |e, _| { missed.read(e, indexfunc) })?
     }
+    Ok(())
+}
 
+
+fn update_date(tr: &postgres::GenericConnection, opt: &PkgOpt, verid: i32) -> std::io::Result<()> {
     match opt.date {
         Date::Known(_) => Ok(()),
         Date::Found(t) | Date::MaxVal(t) => {
@@ -205,7 +481,144 @@ fn index_pkg(tr: &postgres::GenericConnection, mut opt: PkgOpt, verid: i32) -> s
 }
 
 
+// Index a package. If a worker pool has been started, the package is queued and indexed by one of
+// the workers instead, in which case 'conn' isn't used.
 pub fn pkg(conn: &postgres::GenericConnection, opt: PkgOpt) {
+    let tx = POOL.lock().unwrap().as_ref().map(|p| p.tx.clone());
+    match tx {
+        Some(tx) => tx.send(Job::new(&opt)).expect("All worker threads have died"),
+        None => pkg_with(conn, opt, |tr, opt, verid, stubs| index_pkg(tr, opt, verid, stubs, None)),
+    }
+}
+
+
+// Owned copy of a PkgOpt, so that it can be sent to a worker thread.
+struct Job {
+    force: bool,
+    sys: i32,
+    cat: String,
+    pkg: String,
+    ver: String,
+    date: Date<'static>, // Not used if 'known' is set
+    known: Option<String>,
+    arch: Option<String>,
+    digest: Option<(&'static digest::Algorithm, String)>,
+    path: String,
+    cache: bool,
+    canbelocal: bool,
+}
+
+impl Job {
+    fn new(opt: &PkgOpt) -> Job {
+        Job {
+            force: opt.force,
+            sys: opt.sys,
+            cat: opt.cat.to_string(),
+            pkg: opt.pkg.to_string(),
+            ver: opt.ver.to_string(),
+            date: match opt.date {
+                Date::Known(_) | Date::Max => Date::Max,
+                Date::Found(t) => Date::Found(t),
+                Date::Deb => Date::Deb,
+                Date::Desc => Date::Desc,
+                Date::MaxVal(t) => Date::MaxVal(t),
+            },
+            known: match opt.date { Date::Known(d) => Some(d.to_string()), _ => None },
+            arch: opt.arch.map(str::to_string),
+            digest: opt.digest.map(|d| (d.alg, d.hex.to_string())),
+            path: opt.file.path.to_string(),
+            cache: opt.file.cache,
+            canbelocal: opt.file.canbelocal,
+        }
+    }
+
+    // A database error (e.g. a deadlock with another worker) panics somewhere down in pkg_with(),
+    // which rolls back the transaction of this package. Catch that here so that the worker can
+    // continue with the next package.
+    fn run(&self, conn: &postgres::GenericConnection) {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| self.index(conn)));
+        if res.is_err() {
+            error!("Error indexing sys {} / {} / {} - {}, transaction has been rolled back", self.sys, self.cat, self.pkg, self.ver);
+        }
+    }
+
+    fn index(&self, conn: &postgres::GenericConnection) {
+        pkg_with(conn, PkgOpt {
+            force: self.force,
+            sys: self.sys,
+            cat: &self.cat,
+            pkg: &self.pkg,
+            ver: &self.ver,
+            date: match self.known { Some(ref d) => Date::Known(d), None => self.date },
+            arch: self.arch.as_ref().map(|e| &e[..]),
+            digest: self.digest.as_ref().map(|&(alg, ref hex)| Checksum { alg: alg, hex: hex }),
+            file: open::Path {
+                path: &self.path,
+                cache: self.cache,
+                canbelocal: self.canbelocal,
+            },
+        }, |tr, opt, verid, stubs| index_pkg(tr, opt, verid, stubs, None))
+    }
+}
+
+
+struct Pool {
+    tx: SyncSender<Job>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+lazy_static!(
+    static ref POOL: Mutex<Option<Pool>> = Mutex::new(None);
+);
+
+
+// Start a worker thread for each connection, subsequent calls to pkg() are handled by these
+// workers. Each package still gets its own transaction. Concurrent inserts of the same package are
+// serialized by the row lock on the packages table in insert_pkg(), but packages that share man
+// pages also contend on the same rows in the contents table. See insert_contents() for how that is
+// kept to a minimum; a deadlock that still happens only fails the package, see Job::run().
+pub fn start_pool(conns: Vec<postgres::Connection>) {
+    let (tx, rx) = sync_channel::<Job>(conns.len());
+    let rx = Arc::new(Mutex::new(rx));
+    let workers = conns.into_iter().map(|conn| {
+        let rx = rx.clone();
+        thread::spawn(move || loop {
+            let job = match rx.lock().unwrap().recv() { Ok(x) => x, Err(_) => break };
+            job.run(&conn);
+        })
+    }).collect();
+    *POOL.lock().unwrap() = Some(Pool { tx: tx, workers: workers });
+}
+
+
+// Wait for the workers to finish the queued packages.
+pub fn finish_pool() {
+    let pool = POOL.lock().unwrap().take();
+    if let Some(pool) = pool {
+        drop(pool.tx);
+        for w in pool.workers {
+            if w.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+
+// Same as pkg(), but for a package inside an archive (e.g. an ISO image) at opt.file. rd should
+// be the package entry in that archive.
+pub fn pkg_in(conn: &postgres::GenericConnection, opt: PkgOpt, inner: &str, rd: &mut Read) {
+    pkg_with(conn, opt, |tr, opt, verid, stubs| index_pkg(tr, opt, verid, stubs, Some((inner, rd))))
+}
+
+
+// Same as pkg(), but with a custom function to read the man pages from the package. For packages
+// that don't fit into a single archive (see sys_oci). The function is run inside the transaction
+// of the package and gets the verid of the new package and the stubs to pass to insert_man() and
+// friends; opt.date is handled as with pkg().
+pub fn pkg_with<F>(conn: &postgres::GenericConnection, mut opt: PkgOpt, f: F)
+    where F: FnOnce(&postgres::GenericConnection, &mut PkgOpt, i32, &Stubs) -> std::io::Result<()>
+{
     let tr = conn.transaction().unwrap();
     tr.set_rollback();
 
@@ -214,7 +627,9 @@ pub fn pkg(conn: &postgres::GenericConnection, opt: PkgOpt) {
         return;
     }
 
-    match index_pkg(&tr, opt, verid) {
+    let stubs = Stubs::default();
+    let res = f(&tr, &mut opt, verid, &stubs);
+    match res.and_then(|_| { insert_stubs(&tr, verid, stubs); update_date(&tr, &opt, verid) }) {
         Err(e) => error!("Error reading package: {}", e),
         Ok(_) => tr.set_commit()
     }
@@ -223,3 +638,58 @@ pub fn pkg(conn: &postgres::GenericConnection, opt: PkgOpt) {
         error!("Error finishing transaction: {}", e);
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let sum = |hex| PkgOpt {
+            force: false,
+            sys: 0,
+            cat: "",
+            pkg: "",
+            ver: "",
+            date: Date::Max,
+            arch: None,
+            digest: Some(Checksum { alg: &digest::SHA256, hex: hex }),
+            file: open::Path { path: "empty", cache: false, canbelocal: false },
+        };
+        let empty = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        assert!(with_pkg_rd(&mut std::io::empty(), &mut sum(empty), |_, _| Ok(())).is_ok());
+        assert!(with_pkg_rd(&mut std::io::empty(), &mut sum(&empty[1..]), |_, _| Ok(())).is_err());
+    }
+
+    fn stub(path: &str, target: &str) -> Stub {
+        Stub {
+            path: path.to_string(),
+            base: path.to_string(),
+            target: target.to_string(),
+            hash: Vec::new(),
+            enc: "utf-8",
+            cont: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_stubs() {
+        let mut pages = HashMap::new();
+        pages.insert("man/man1/real.1".to_string(), (vec![1u8], "utf-8".to_string()));
+        let stubs = vec![
+            // Chain: a -> b -> real
+            stub("man/man1/a.1.gz", "man1/b.1"),
+            stub("man/man1/b.1", "man1/real.1.gz"),
+            // Loop: x -> y -> x
+            stub("man/man1/x.1", "man1/y.1"),
+            stub("man/man1/y.1", "man1/x.1"),
+            // Not in the package
+            stub("man/man1/z.1", "man1/missing.1"),
+            // Link to a stub, the .so request is relative to the stub rather than the link
+            Stub { path: "man/man3/alias.3".to_string(), ..stub("man/man1/a.1.gz", "man1/b.1") },
+        ];
+        let real = Some("man/man1/real.1".to_string());
+        assert_eq!(resolve_stubs(&pages, &stubs), vec![real.clone(), real.clone(), None, None, None, real]);
+    }
+}
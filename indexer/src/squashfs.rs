@@ -0,0 +1,483 @@
+use std::io::{Result,Error,ErrorKind,Read,Seek,SeekFrom};
+use std::fs::File;
+use std::rc::Rc;
+use std::cmp;
+use std::collections::HashMap;
+
+use archive::{Archive,Entry,FileType};
+use locale;
+
+
+/* A minimal read-only squashfs (4.0) reader, since libarchive doesn't support the format. It
+ * provides the same walk-through-all-entries interface as archive::ArchiveEntry, so that it can be
+ * used with archread::FileList.
+ *
+ * Reference: https://dr-emann.github.io/squashfs/
+ *
+ * Decompression is handled by libarchive's raw format reader, which limits the supported
+ * compression methods to lzma, xz and zstd. That covers snaps, which use xz by default. gzip is,
+ * despite its name, a raw zlib stream which libarchive can't read.
+ *
+ * Hard links are reported as separate regular files, like old-style cpio archives. Xattrs, uid/gid
+ * and the export table are ignored. File names and symlink targets that aren't valid UTF-8 are
+ * decoded with locale::decode_path().
+ */
+
+const MAGIC: u32 = 0x73717368;
+const NOFRAG: u32 = 0xFFFFFFFF;
+
+
+fn le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+fn read_u16(rd: &mut Read) -> Result<u16> { let mut b = [0u8; 2]; rd.read_exact(&mut b)?; Ok(le(&b) as u16) }
+fn read_u32(rd: &mut Read) -> Result<u32> { let mut b = [0u8; 4]; rd.read_exact(&mut b)?; Ok(le(&b) as u32) }
+fn read_u64(rd: &mut Read) -> Result<u64> { let mut b = [0u8; 8]; rd.read_exact(&mut b)?; Ok(le(&b)) }
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid squashfs image: {}", msg))
+}
+
+
+struct SquashFs {
+    fd: File,
+    comp: u16,
+    block_size: u32,
+    inode_table: u64,
+    dir_table: u64,
+    frag_table: u64,
+    // Cache of decompressed metadata blocks: disk position -> (data, position of the next block)
+    meta: HashMap<u64, (Rc<Vec<u8>>, u64)>,
+}
+
+
+enum Inode {
+    Dir { block: u32, offset: u16, size: u32 },
+    File { start: u64, size: u64, blocks: Vec<u32>, frag: u32, frag_offset: u32 },
+    Symlink(Vec<u8>),
+    Other,
+}
+
+
+// Reads a sequence of metadata blocks as a single stream.
+struct MetaReader<'a> {
+    fs: &'a mut SquashFs,
+    next: u64,
+    buf: Rc<Vec<u8>>,
+    off: usize,
+}
+
+
+pub struct SquashEntry {
+    fs: Box<SquashFs>,
+    // Entries that have yet to be visited, in reverse order: (path, inode reference)
+    stack: Vec<(String, u64)>,
+    path: String,
+    mtime: u32,
+    inode: Inode,
+    // State for reading file contents
+    blk: usize,
+    pos: u64,
+    remaining: u64,
+    buf: Vec<u8>,
+    bufoff: usize,
+}
+
+
+impl<'a> Read for MetaReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.off >= self.buf.len() {
+            let (data, next) = self.fs.meta_block(self.next)?;
+            self.buf = data;
+            self.next = next;
+            self.off = 0;
+        }
+        let len = cmp::min(buf.len(), self.buf.len() - self.off);
+        buf[..len].copy_from_slice(&self.buf[self.off..self.off+len]);
+        self.off += len;
+        Ok(len)
+    }
+}
+
+
+impl SquashFs {
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self.comp {
+            2 | 4 | 6 => {
+                let mut out = Vec::new();
+                let mut rd = data;
+                Archive::open_raw(&mut rd)?.read_to_end(&mut out)?;
+                Ok(out)
+            },
+            c => Err(Error::new(ErrorKind::Other, format!("Unsupported squashfs compression method: {}", c))),
+        }
+    }
+
+    fn read_at(&mut self, pos: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        self.fd.seek(SeekFrom::Start(pos))?;
+        self.fd.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn meta_block(&mut self, pos: u64) -> Result<(Rc<Vec<u8>>, u64)> {
+        if let Some(&(ref data, next)) = self.meta.get(&pos) {
+            return Ok((data.clone(), next));
+        }
+        let hdr = le(&self.read_at(pos, 2)?) as u16;
+        let len = (hdr & 0x7FFF) as usize;
+        let data = self.read_at(pos + 2, len)?;
+        let data = Rc::new(if hdr & 0x8000 == 0 { self.decompress(&data)? } else { data });
+        if data.len() == 0 {
+            return Err(invalid("empty metadata block"));
+        }
+        let next = pos + 2 + len as u64;
+        self.meta.insert(pos, (data.clone(), next));
+        Ok((data, next))
+    }
+
+    // Returns a reader starting at the given offset into the (uncompressed) block at 'pos'.
+    fn meta(&mut self, pos: u64, off: usize) -> Result<MetaReader> {
+        let (buf, next) = self.meta_block(pos)?;
+        if off > buf.len() {
+            return Err(invalid("metadata offset out of range"));
+        }
+        Ok(MetaReader { fs: self, next: next, buf: buf, off: off })
+    }
+
+    fn inode(&mut self, iref: u64) -> Result<(u32, Inode)> {
+        let bs = self.block_size as u64;
+        let pos = self.inode_table + (iref >> 16);
+        let mut rd = self.meta(pos, (iref & 0xFFFF) as usize)?;
+        let itype = read_u16(&mut rd)?;
+        let mut hdr = [0u8; 14]; // permissions, uid, gid, mtime, inode number
+        rd.read_exact(&mut hdr)?;
+        let mtime = le(&hdr[6..10]) as u32;
+
+        let inode = match itype {
+            1 => {
+                let block = read_u32(&mut rd)?;
+                let _nlink = read_u32(&mut rd)?;
+                let size = read_u16(&mut rd)? as u32;
+                let offset = read_u16(&mut rd)?;
+                Inode::Dir { block: block, offset: offset, size: size }
+            },
+            8 => {
+                let _nlink = read_u32(&mut rd)?;
+                let size = read_u32(&mut rd)?;
+                let block = read_u32(&mut rd)?;
+                let _parent = read_u32(&mut rd)?;
+                let _index_count = read_u16(&mut rd)?;
+                let offset = read_u16(&mut rd)?;
+                Inode::Dir { block: block, offset: offset, size: size }
+            },
+            2 | 9 => {
+                let (start, frag, frag_offset, size) = if itype == 2 {
+                    let start = read_u32(&mut rd)? as u64;
+                    let frag = read_u32(&mut rd)?;
+                    let frag_offset = read_u32(&mut rd)?;
+                    (start, frag, frag_offset, read_u32(&mut rd)? as u64)
+                } else {
+                    let start = read_u64(&mut rd)?;
+                    let size = read_u64(&mut rd)?;
+                    let _sparse = read_u64(&mut rd)?;
+                    let _nlink = read_u32(&mut rd)?;
+                    let frag = read_u32(&mut rd)?;
+                    let frag_offset = read_u32(&mut rd)?;
+                    let _xattr = read_u32(&mut rd)?;
+                    (start, frag, frag_offset, size)
+                };
+                let num = if frag == NOFRAG {
+                    size.checked_add(bs - 1).ok_or_else(|| invalid("file size too large"))? / bs
+                } else {
+                    size / bs
+                };
+                // Not preallocated, num is untrusted and the inode may well be truncated.
+                let mut blocks = Vec::new();
+                for _ in 0..num {
+                    blocks.push(read_u32(&mut rd)?);
+                }
+                Inode::File { start: start, size: size, blocks: blocks, frag: frag, frag_offset: frag_offset }
+            },
+            3 | 10 => {
+                let _nlink = read_u32(&mut rd)?;
+                let len = read_u32(&mut rd)? as u64;
+                let mut target = Vec::new();
+                rd.by_ref().take(len).read_to_end(&mut target)?;
+                Inode::Symlink(target)
+            },
+            4..=7 | 11..=14 => Inode::Other,
+            _ => return Err(invalid("unknown inode type")),
+        };
+        Ok((mtime, inode))
+    }
+
+    // Returns the (name, inode reference) of each entry in a directory.
+    fn readdir(&mut self, block: u32, offset: u16, size: u32) -> Result<Vec<(Vec<u8>, u64)>> {
+        let mut ents = Vec::new();
+        // The size includes the (non-existent) "." and ".." entries
+        if size <= 3 {
+            return Ok(ents);
+        }
+        let pos = self.dir_table + block as u64;
+        let mut rd = self.meta(pos, offset as usize)?.take(size as u64 - 3);
+        let mut buf = Vec::new();
+        rd.read_to_end(&mut buf)?;
+
+        let mut rd = &buf[..];
+        while rd.len() > 0 {
+            let count = read_u32(&mut rd)? + 1;
+            let start = read_u32(&mut rd)? as u64;
+            let _inode_number = read_u32(&mut rd)?;
+            for _ in 0..count {
+                let offset = read_u16(&mut rd)? as u64;
+                let _inode_offset = read_u16(&mut rd)?;
+                let _itype = read_u16(&mut rd)?;
+                let len = read_u16(&mut rd)? as usize + 1;
+                if rd.len() < len {
+                    return Err(invalid("truncated directory entry"));
+                }
+                ents.push((rd[..len].to_vec(), (start << 16) | offset));
+                rd = &rd[len..];
+            }
+        }
+        Ok(ents)
+    }
+
+    // Returns the contents of the fragment block, trimmed to the part belonging to the file.
+    fn fragment(&mut self, idx: u32, offset: u32, len: u64) -> Result<Vec<u8>> {
+        let ptr = read_u64(&mut &self.read_at(self.frag_table + (idx / 512) as u64 * 8, 8)?[..])?;
+        let (start, size) = {
+            let mut rd = self.meta(ptr, (idx % 512) as usize * 16)?;
+            (read_u64(&mut rd)?, read_u32(&mut rd)?)
+        };
+        let data = self.read_at(start, (size & 0xFFFFFF) as usize)?;
+        let data = if size & (1<<24) == 0 { self.decompress(&data)? } else { data };
+        let (offset, len) = (offset as usize, len as usize);
+        if offset + len > data.len() {
+            return Err(invalid("fragment out of range"));
+        }
+        Ok(data[offset..offset+len].to_vec())
+    }
+}
+
+
+impl SquashEntry {
+    pub fn open(path: &str) -> Result<Option<SquashEntry>> {
+        let mut fd = File::open(path)?;
+        let mut sb = [0u8; 96];
+        fd.read_exact(&mut sb)?;
+        if le(&sb[0..4]) as u32 != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if le(&sb[28..30]) != 4 {
+            return Err(Error::new(ErrorKind::Other, "Unsupported squashfs version"));
+        }
+        let block_size = le(&sb[12..16]) as u32;
+        if block_size < 4096 || block_size > 1<<20 || !block_size.is_power_of_two() {
+            return Err(invalid("bad block size"));
+        }
+
+        let mut fs = Box::new(SquashFs {
+            fd: fd,
+            comp: le(&sb[20..22]) as u16,
+            block_size: block_size,
+            inode_table: le(&sb[64..72]),
+            dir_table: le(&sb[72..80]),
+            frag_table: le(&sb[80..88]),
+            meta: HashMap::new(),
+        });
+        let root = le(&sb[32..40]);
+
+        let mut stack = Vec::new();
+        if let (_, Inode::Dir{block, offset, size}) = fs.inode(root)? {
+            stack = fs.readdir(block, offset, size)?.into_iter().rev().map(|(n, r)| (locale::join_path("", &n), r)).collect();
+        }
+        Self::entry(fs, stack)
+    }
+
+    fn entry(mut fs: Box<SquashFs>, mut stack: Vec<(String, u64)>) -> Result<Option<SquashEntry>> {
+        let (path, iref) = match stack.pop() { Some(x) => x, None => return Ok(None) };
+        let (mtime, inode) = fs.inode(iref)?;
+
+        if let Inode::Dir{block, offset, size} = inode {
+            let ents = fs.readdir(block, offset, size)?;
+            stack.extend(ents.into_iter().rev().map(|(n, r)| (locale::join_path(&path, &n), r)));
+        }
+
+        let remaining = match inode { Inode::File{size, ..} => size, _ => 0 };
+        let pos = match inode { Inode::File{start, ..} => start, _ => 0 };
+        Ok(Some(SquashEntry {
+            fs: fs,
+            stack: stack,
+            path: path,
+            mtime: mtime,
+            inode: inode,
+            blk: 0,
+            pos: pos,
+            remaining: remaining,
+            buf: Vec::new(),
+            bufoff: 0,
+        }))
+    }
+
+    // Read the next data block (or the fragment) of the file into self.buf.
+    fn fill(&mut self) -> Result<()> {
+        let bs = self.fs.block_size as u64;
+        let (blocks, frag, frag_offset) = match self.inode {
+            Inode::File{ref blocks, frag, frag_offset, ..} => (blocks, frag, frag_offset),
+            _ => return Ok(()),
+        };
+        let len = cmp::min(bs, self.remaining);
+        let data = if self.blk < blocks.len() {
+            let size = blocks[self.blk];
+            self.blk += 1;
+            let disk = (size & 0xFFFFFF) as usize;
+            if disk == 0 {
+                vec![0u8; len as usize] // Sparse block
+            } else {
+                let data = self.fs.read_at(self.pos, disk)?;
+                self.pos += disk as u64;
+                if size & (1<<24) == 0 { self.fs.decompress(&data)? } else { data }
+            }
+        } else if frag != NOFRAG {
+            self.fs.fragment(frag, frag_offset, len)?
+        } else {
+            return Err(invalid("file data out of range"));
+        };
+        if (data.len() as u64) < len {
+            return Err(invalid("truncated data block"));
+        }
+        self.buf = data;
+        self.buf.truncate(len as usize);
+        self.bufoff = 0;
+        self.remaining -= len;
+        Ok(())
+    }
+}
+
+
+impl Entry for SquashEntry {
+    fn next(self) -> Result<Option<SquashEntry>> {
+        Self::entry(self.fs, self.stack)
+    }
+
+    fn path(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+
+    fn size(&self) -> usize {
+        match self.inode { Inode::File{size, ..} => size as usize, _ => 0 }
+    }
+
+    fn mtime(&self) -> i64 {
+        self.mtime as i64
+    }
+
+    fn filetype(&self) -> FileType {
+        match self.inode {
+            Inode::Dir{..} => FileType::Directory,
+            Inode::File{..} => FileType::File,
+            Inode::Symlink(ref t) => FileType::Link(locale::decode_path(t, self.path.as_bytes())),
+            Inode::Other => FileType::Other,
+        }
+    }
+}
+
+
+impl Read for SquashEntry {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.bufoff >= self.buf.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let len = cmp::min(buf.len(), self.buf.len() - self.bufoff);
+        buf[..len].copy_from_slice(&self.buf[self.bufoff..self.bufoff+len]);
+        self.bufoff += len;
+        Ok(len)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use archive::walk;
+    use archread::FileList;
+
+    fn large() -> String {
+        let s: String = (1..2001).map(|i| format!(".\\\" line {}\n", i)).collect();
+        s[..10000].to_string()
+    }
+
+    fn exact() -> String {
+        ".\\\" padding\n".repeat(700)[..8192].to_string()
+    }
+
+    #[test]
+    fn test_walk() {
+        let mut ents = Vec::new();
+        walk(SquashEntry::open("tests/squashfs.squashfs").unwrap(), |e| {
+            let mut cont = String::new();
+            assert_eq!(e.read_to_string(&mut cont)?, e.size());
+            ents.push((e.path().unwrap().to_string(), e.filetype(), cont));
+            Ok(true)
+        }).unwrap();
+
+        let d = |p: &str| (p.to_string(), FileType::Directory, String::new());
+        let f = |p: &str, c: String| (p.to_string(), FileType::File, c);
+        let l = |p: &str, t: &str| (p.to_string(), FileType::Link(t.to_string()), String::new());
+        assert_eq!(ents, vec![
+            d("usr"),
+            d("usr/share"),
+            d("usr/share/empty"),
+            d("usr/share/man"),
+            d("usr/share/man/man1"),
+            f("usr/share/man/man1/exact.1", exact()), // Two full blocks
+            f("usr/share/man/man1/large.1", large()), // Two full blocks and a fragment
+            l("usr/share/man/man1/link.1", "small.1"),
+            f("usr/share/man/man1/small.1", ".TH SMALL 1\n".to_string()), // Fragment only
+            d("usr/share/man/man8"),
+            l("usr/share/man/man8/abs.8", "/usr/share/man/man1/large.1"),
+            d("usr/share/man/ru"),
+            d("usr/share/man/ru/man1"),
+            l("usr/share/man/ru/man1/link.1", "тест.1"), // KOI8-R file names
+            f("usr/share/man/ru/man1/тест.1", "KOI8-R file name\n".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_filelist() {
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        let missed = FileList::read(SquashEntry::open("tests/squashfs.squashfs").unwrap(), |p| p.starts_with("usr/share/man/"), |_| (), |p, e| {
+            let mut cont = String::new();
+            e.read_to_string(&mut cont)?;
+            files.push((p.join(","), cont.len()));
+            Ok(())
+        }, |_| ()).unwrap().links(|src, dest| links.push((src.to_string(), dest.to_string())));
+
+        assert!(missed.is_none());
+        assert_eq!(files, vec![
+            ("usr/share/man/man1/exact.1".to_string(), 8192),
+            ("usr/share/man/man1/large.1".to_string(), 10000),
+            ("usr/share/man/man1/small.1".to_string(), 12),
+            ("usr/share/man/ru/man1/тест.1".to_string(), 17),
+        ]);
+        assert_eq!(links, vec![
+            ("usr/share/man/man1/link.1".to_string(), "usr/share/man/man1/small.1".to_string()),
+            ("usr/share/man/man8/abs.8".to_string(), "usr/share/man/man1/large.1".to_string()),
+            ("usr/share/man/ru/man1/link.1".to_string(), "usr/share/man/ru/man1/тест.1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_gzip() {
+        // mksquashfs' default compression, not supported by libarchive
+        let err = SquashEntry::open("tests/squashfs-gzip.squashfs").err().unwrap();
+        assert_eq!(err.to_string(), "Unsupported squashfs compression method: 1");
+    }
+}
@@ -0,0 +1,127 @@
+use std::str::FromStr;
+use std::io::{BufRead,BufReader,Result,Error,ErrorKind};
+use chrono::NaiveDateTime;
+use postgres;
+
+use archive::{Archive,ArchiveEntry};
+use open;
+use pkg;
+
+// Reference: https://wiki.alpinelinux.org/wiki/Apk_spec
+
+
+#[derive(Default,Debug,PartialEq)]
+struct Meta {
+    name: Option<String>,
+    version: Option<String>,
+    arch: Option<String>,
+    date: Option<String>,
+}
+
+
+fn getindex(mut ent: Option<ArchiveEntry>) -> Result<ArchiveEntry> {
+    while let Some(e) = ent {
+        if e.path() == Some("APKINDEX") {
+            return Ok(e)
+        }
+        ent = e.next()?
+    }
+    Err(Error::new(ErrorKind::Other, "No APKINDEX found"))
+}
+
+
+fn handlepkg(pg: &postgres::GenericConnection, sys: i32, mirror: &str, repo: &str, arch: &str, m: &Meta) {
+    let name = match m.name { Some(ref x) => x, None => return };
+    // Alpine moves all man pages into separate -doc packages, other packages don't need to be
+    // downloaded.
+    if !name.ends_with("-doc") {
+        return;
+    }
+    let version = match m.version { Some(ref x) => x, None => { error!("Package {} has no version", name); return } };
+    let date    = match m.date    { Some(ref x) => x, None => { error!("Package {} has no valid build date", name); return } };
+
+    let p = format!("{}/{}/{}/{}-{}.apk", mirror, repo, arch, name, version);
+    pkg::pkg(pg, pkg::PkgOpt{
+        force: false,
+        sys: sys,
+        cat: repo,
+        pkg: &name,
+        ver: &version,
+        date: pkg::Date::Known(date),
+        arch: Some(m.arch.as_ref().map(|e| &e[..]).unwrap_or(arch)),
+        digest: None,
+        file: open::Path{
+            path: &p,
+            cache: false,
+            canbelocal: false,
+        },
+    });
+}
+
+
+fn readindex<R: BufRead, F: FnMut(Meta)>(brd: R, mut cb: F) -> Result<()> {
+    let mut m = Meta::default();
+    for line in brd.lines() {
+        let line = line?;
+        if line.trim() == "" {
+            if m.name.is_some() {
+                cb(m);
+            }
+            m = Meta::default();
+            continue;
+        }
+        let mut kv = line.splitn(2, ':');
+        let (key, val) = match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => (k, v),
+            _ => continue,
+        };
+        match key {
+            "P" => m.name    = Some(val.to_string()),
+            "V" => m.version = Some(val.to_string()),
+            "A" => m.arch    = Some(val.to_string()),
+            "t" => m.date    = i64::from_str(val).ok()
+                .and_then(|t| NaiveDateTime::from_timestamp_opt(t, 0))
+                .map(|t| t.format("%Y-%m-%d").to_string()),
+            _ => {},
+        }
+    }
+    if m.name.is_some() {
+        cb(m);
+    }
+    Ok(())
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str, repo: &str, arch: &str) -> Result<()> {
+    info!("Reading packages from {} {} {}", mirror, repo, arch);
+
+    let path = format!("{}/{}/{}/APKINDEX.tar.gz", mirror, repo, arch);
+    let mut rd = open::Path{path: &path, cache: true, canbelocal: false}.open()?;
+
+    // APKINDEX.tar.gz is a concatenation of a signature and the actual index as separate gzip
+    // streams, libarchive reads this as a single tar file.
+    let ent = Archive::open_archive(&mut rd)?;
+    readindex(BufReader::new(getindex(ent)?), |m| handlepkg(pg, sys, mirror, repo, arch, &m))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_readindex() {
+        let f = File::open("tests/APKINDEX").unwrap();
+        let mut pkgs = Vec::new();
+        readindex(BufReader::new(f), |m| pkgs.push(m)).unwrap();
+
+        let s = |x: &str| Some(x.to_string());
+        assert_eq!(pkgs, vec![
+            Meta { name: s("ncdu-doc"), version: s("1.13-r0"), arch: s("noarch"), date: s("2018-02-04") },
+            Meta { name: s("ncdu"), version: s("1.13-r0"), arch: s("x86_64"), date: s("2018-02-04") },
+            // Out of range build date
+            Meta { name: s("broken-doc"), version: s("1.0-r0"), arch: s("noarch"), date: None },
+        ]);
+    }
+}
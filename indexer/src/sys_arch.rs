@@ -16,6 +16,7 @@ struct Meta {
     version: String,
     date: String,
     arch: Option<String>,
+    sha256: Option<String>,
 }
 
 
@@ -45,6 +46,7 @@ fn read_desc(rd: &mut archive::ArchiveEntry) -> Result<Option<Meta>> {
     let mut version = None;
     let mut builddate = None;
     let mut arch = None;
+    let mut sha256 = None;
 
     for kv in RE.captures_iter(&data) {
         let key = &kv[1];
@@ -56,6 +58,7 @@ fn read_desc(rd: &mut archive::ArchiveEntry) -> Result<Option<Meta>> {
             "VERSION"   => version   = Some(val),
             "BUILDDATE" => builddate = i64::from_str(val).ok(),
             "ARCH"      => arch      = Some(val),
+            "SHA256SUM" => sha256    = Some(val),
             _ => {},
         }
     }
@@ -67,6 +70,7 @@ fn read_desc(rd: &mut archive::ArchiveEntry) -> Result<Option<Meta>> {
             version: version.unwrap().to_string(),
             date: NaiveDateTime::from_timestamp(builddate.unwrap(), 0).format("%Y-%m-%d").to_string(),
             arch: arch.map(str::to_string),
+            sha256: sha256.map(str::to_string),
         }))
     } else {
         warn!("Metadata missing from package description: {}", path);
@@ -115,6 +119,7 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str, repo: &str
                 ver: &m.version,
                 date: pkg::Date::Known(&m.date),
                 arch: m.arch.as_ref().map(|e| &e[..]),
+                digest: m.sha256.as_ref().and_then(|e| pkg::Checksum::new("sha256", e)),
                 file: open::Path{
                     path: &p,
                     cache: false,
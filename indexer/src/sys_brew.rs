@@ -0,0 +1,203 @@
+use std::io::{Read,Result,Error,ErrorKind};
+use std::fs::read_dir;
+use std::collections::HashMap;
+use regex::Regex;
+use postgres;
+use serde_json;
+use serde_json::Value;
+
+use open;
+use pkg;
+
+// Homebrew bottles are .tar.gz files with the files under <formula>/<version>/, so man pages end up
+// in e.g. wget/1.21.3/share/man/man1/wget.1.
+//
+// Bottles can be indexed from a local directory or from the JSON formula dump (the output of "brew
+// info --json=v2 --eval-all", or https://formulae.brew.sh/api/formula.json). The JSON has a URL
+// for each bottle, but as those point to a registry that requires authentication, a mirror with
+// the traditional "<formula>--<version>.<tag>.bottle[.<rebuild>].tar.gz" layout can be used
+// instead.
+//
+// A bottle is built for several platforms ("tags"), but the man pages are almost always the same,
+// so only one bottle is indexed for each formula version.
+
+
+#[derive(Debug,PartialEq,Eq)]
+struct Bottle {
+    name: String,
+    version: String,
+    tag: String,
+    path: String,
+}
+
+
+// Parse "<formula>--<version>.<tag>.bottle[.<rebuild>].tar.gz"
+fn parse_filename(f: &str) -> Option<(&str, &str, &str)> {
+    lazy_static!(
+        static ref RE: Regex = Regex::new(r"^(.+?)--(.+)\.([a-z0-9_]+)\.bottle(?:\.[0-9]+)?\.tar\.gz$").unwrap();
+    );
+    RE.captures(f).map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str(), c.get(3).unwrap().as_str()))
+}
+
+
+// Map a bottle tag to an architecture. Tags without an architecture prefix are for Intel macOS.
+fn tag_arch(tag: &str) -> &str {
+    if tag == "all" {
+        "all"
+    } else if tag.starts_with("arm64_") {
+        "arm64"
+    } else if tag.ends_with("_linux") {
+        &tag[..tag.len()-6]
+    } else {
+        "x86_64"
+    }
+}
+
+
+// Lower is better: Prefer bottles for all platforms, then Linux (which tends to be built the most
+// reliably), then whatever comes first.
+fn tag_pref(tag: &str) -> (u8, &str) {
+    (if tag == "all" { 0 } else if tag == "x86_64_linux" { 1 } else { 2 }, tag)
+}
+
+
+fn read_dir_bottles(dir: &str) -> Result<Vec<Bottle>> {
+    let mut res = Vec::new();
+    for f in read_dir(dir)? {
+        let f = f?.file_name();
+        let f = match f.to_str() { Some(x) => x, None => continue };
+        if let Some((name, ver, tag)) = parse_filename(f) {
+            res.push(Bottle {
+                name: name.to_string(),
+                version: ver.to_string(),
+                tag: tag.to_string(),
+                path: format!("{}/{}", dir.trim_right_matches('/'), f),
+            });
+        }
+    }
+    Ok(res)
+}
+
+
+fn read_json(rd: &mut Read, mirror: Option<&str>) -> Result<Vec<Bottle>> {
+    let formulae: Value = serde_json::from_reader(rd)?;
+    let mut res = Vec::new();
+    for f in formulae.as_array().map(|a| &a[..]).unwrap_or(&[]) {
+        let (name, ver) = match (f["name"].as_str(), f["versions"]["stable"].as_str()) {
+            (Some(n), Some(v)) => (n, v),
+            _ => continue,
+        };
+        let version = match f["revision"].as_u64() {
+            Some(r) if r > 0 => format!("{}_{}", ver, r),
+            _ => ver.to_string(),
+        };
+        let stable = &f["bottle"]["stable"];
+        let rebuild = match stable["rebuild"].as_u64() {
+            Some(r) if r > 0 => format!(".{}", r),
+            _ => String::new(),
+        };
+        for (tag, b) in stable["files"].as_object().into_iter().flat_map(|o| o.iter()) {
+            let path = match mirror {
+                Some(m) => format!("{}{}--{}.{}.bottle{}.tar.gz", m, name, version, tag, rebuild),
+                None => match b["url"].as_str() { Some(u) => u.to_string(), None => continue },
+            };
+            res.push(Bottle {
+                name: name.to_string(),
+                version: version.clone(),
+                tag: tag.to_string(),
+                path: path,
+            });
+        }
+    }
+    Ok(res)
+}
+
+
+// Select a single bottle for each formula version.
+fn select(bottles: Vec<Bottle>) -> Vec<Bottle> {
+    let mut sel: HashMap<(String, String), Bottle> = HashMap::new();
+    for b in bottles {
+        let k = (b.name.clone(), b.version.clone());
+        if sel.get(&k).map(|o| tag_pref(&b.tag) < tag_pref(&o.tag)).unwrap_or(true) {
+            sel.insert(k, b);
+        }
+    }
+    let mut res: Vec<Bottle> = sel.into_iter().map(|(_,v)| v).collect();
+    res.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    res
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, dir: Option<&str>, json: Option<&str>, mirror: Option<&str>) -> Result<()> {
+    let bottles = match (dir, json) {
+        (Some(d), _) => read_dir_bottles(d)?,
+        (None, Some(j)) => {
+            let mut rd = open::Path{path: j, cache: true, canbelocal: true}.open()?;
+            read_json(&mut rd, mirror)?
+        },
+        (None, None) => return Err(Error::new(ErrorKind::Other, "Either a bottle directory or a JSON formula dump is required")),
+    };
+
+    for b in select(bottles) {
+        pkg::pkg(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: cat,
+            pkg: &b.name,
+            ver: &b.version,
+            date: pkg::Date::Max,
+            arch: Some(tag_arch(&b.tag)),
+            digest: None,
+            file: open::Path{
+                path: &b.path,
+                cache: false,
+                canbelocal: dir.is_some(),
+            },
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filename() {
+        assert_eq!(parse_filename("wget--1.21.3.arm64_ventura.bottle.tar.gz"), Some(("wget", "1.21.3", "arm64_ventura")));
+        assert_eq!(parse_filename("ncdu--2.2.1_1.x86_64_linux.bottle.2.tar.gz"), Some(("ncdu", "2.2.1_1", "x86_64_linux")));
+        assert_eq!(parse_filename("go--1.20.high_sierra.bottle.tar.gz"), Some(("go", "1.20", "high_sierra")));
+        assert_eq!(parse_filename("wget-1.21.3.tar.gz"), None);
+    }
+
+    #[test]
+    fn test_tag_arch() {
+        assert_eq!(tag_arch("arm64_sonoma"), "arm64");
+        assert_eq!(tag_arch("x86_64_linux"), "x86_64");
+        assert_eq!(tag_arch("aarch64_linux"), "aarch64");
+        assert_eq!(tag_arch("monterey"), "x86_64");
+        assert_eq!(tag_arch("all"), "all");
+    }
+
+    #[test]
+    fn test_read_json() {
+        let data = r#"[
+            {"name": "ncdu", "versions": {"stable": "2.2.1"}, "revision": 1, "bottle": {"stable": {"rebuild": 0, "files": {
+                "arm64_ventura": {"url": "https://ghcr.io/v2/homebrew/core/ncdu/blobs/sha256:aa"},
+                "x86_64_linux": {"url": "https://ghcr.io/v2/homebrew/core/ncdu/blobs/sha256:bb"}
+            }}}},
+            {"name": "tree", "versions": {"stable": "2.1.0"}, "revision": 0, "bottle": {"stable": {"rebuild": 2, "files": {
+                "ventura": {"url": "https://ghcr.io/v2/homebrew/core/tree/blobs/sha256:cc"}
+            }}}},
+            {"name": "nobottle", "versions": {"stable": "1.0"}, "bottle": {}}
+        ]"#;
+        let b = select(read_json(&mut data.as_bytes(), None).unwrap());
+        assert_eq!(b.len(), 2);
+        assert_eq!((&b[0].version as &str, &b[0].tag as &str, &b[0].path as &str),
+            ("2.2.1_1", "x86_64_linux", "https://ghcr.io/v2/homebrew/core/ncdu/blobs/sha256:bb"));
+
+        let b = select(read_json(&mut data.as_bytes(), Some("http://mirror/bottles/")).unwrap());
+        assert_eq!(b[1].path, "http://mirror/bottles/tree--2.1.0.ventura.bottle.2.tar.gz");
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::{Read,BufRead,BufReader,Result};
+use std::collections::HashMap;
+use regex::Regex;
+use postgres;
+
+use archive;
+use open;
+use pkg;
+
+// Shared backend for OpenBSD and NetBSD. Both systems distribute their base system as a set of
+// tarballs ("sets") in a single directory, and both use the old pkg_create package format (a .tgz
+// with +CONTENTS and friends as the first entries) for third-party packages.
+
+
+// Sets that may contain man pages. Everything in the set directory with a name like
+// "<set>[<version>].tgz" or "<set>.tar.xz" is considered.
+//   man:    All base system man pages (both systems)
+//   comp:   Compiler/development tools, includes man pages on some releases
+//   xshare: X11 man pages (OpenBSD)
+const SETS: &'static [&'static str] = &["man", "comp", "xshare"];
+
+
+// Index the base sets in a directory listing, each set is considered to be a single package in the
+// "base" category with the release version as package version.
+pub fn sets(pg: &postgres::GenericConnection, sys: i32, ver: &str, arch: &str, mirror: &str) -> Result<()> {
+    lazy_static!(
+        // OpenBSD: man63.tgz, NetBSD: man.tgz or man.tar.xz
+        static ref RE: Regex = Regex::new(r"^([a-z]+)[0-9]*\.(?:tgz|tar\.xz)$").unwrap();
+    );
+
+    let files = open::Path{path: mirror, cache: true, canbelocal: false}.dirlist()?;
+    for (f, _) in files.into_iter().filter(|&(_,d)| !d) {
+        let set = match RE.captures(&f) {
+            Some(c) => c[1].to_string(),
+            None => continue,
+        };
+        if !SETS.contains(&&set[..]) {
+            trace!("Ignoring set {}", f);
+            continue;
+        }
+
+        let path = format!("{}{}", mirror, f);
+        pkg::pkg(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: "base",
+            pkg: &set,
+            ver: ver,
+            date: pkg::Date::Max,
+            arch: Some(arch),
+            digest: None,
+            file: open::Path{
+                path: &path,
+                cache: false,
+                canbelocal: false,
+            },
+        });
+    }
+    Ok(())
+}
+
+
+// Read the category from the package metadata. The +CONTENTS file on OpenBSD has a
+// "@comment pkgpath=<category>/<name>[,flavor]" line, NetBSD has a "PKGPATH=<category>/<name>" in
+// +BUILD_INFO. (+COMMENT only has a short description, for which we have no use)
+//
+// This only reads the first few entries of the package, the rest isn't downloaded.
+fn read_category(path: &str) -> Result<Option<String>> {
+    lazy_static!(
+        static ref RE: Regex = Regex::new(r"(?m)^(?:@comment pkgpath=|PKGPATH=)([^/\s]+)/").unwrap();
+    );
+
+    let mut rd = open::Path{path: path, cache: false, canbelocal: false}.open()?;
+    let ent = archive::Archive::open_archive(&mut rd)?;
+    let mut cat = None;
+    archive::walk(ent, |e| {
+        let p = match e.path() { Some(p) => p.to_string(), None => return Ok(false) };
+        if !p.starts_with('+') {
+            return Ok(false);
+        }
+        if p == "+CONTENTS" || p == "+BUILD_INFO" {
+            let mut data = Vec::new();
+            e.take(1024*1024).read_to_end(&mut data)?;
+            cat = RE.captures(&String::from_utf8_lossy(&data)).map(|c| c[1].to_string());
+        }
+        Ok(cat.is_none())
+    })?;
+    Ok(cat)
+}
+
+
+// Read pkgsrc's pkg_summary, which has the PKGPATH of every package in the directory. Returns a
+// map from file name to category.
+fn read_summary<R: BufRead>(rd: R) -> Result<HashMap<String, String>> {
+    let mut res = HashMap::new();
+    let (mut name, mut file, mut cat) = (None, None, None);
+    for line in rd.split(b'\n') {
+        let line = line?;
+        let line = String::from_utf8_lossy(&line);
+        let mut kv = line.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some("PKGNAME"), Some(v)) => name = Some(v.to_string()),
+            (Some("FILE_NAME"), Some(v)) => file = Some(v.to_string()),
+            (Some("PKGPATH"), Some(v)) => cat = v.split('/').next().map(str::to_string),
+            (Some(""), None) => {
+                if let (Some(f), Some(c)) = (file.take().or(name.take().map(|n| format!("{}.tgz", n))), cat.take()) {
+                    res.insert(f, c);
+                }
+                name = None;
+                cat = None;
+            },
+            _ => (),
+        }
+    }
+    if let (Some(f), Some(c)) = (file.or(name.map(|n| format!("{}.tgz", n))), cat) {
+        res.insert(f, c);
+    }
+    Ok(res)
+}
+
+
+// Split a package file name into name and version.
+// pkgsrc: <name>-<version>.tgz, version doesn't have a dash.
+// OpenBSD: <stem>-<version>[-<flavor>..].tgz, flavors don't start with a digit.
+fn splitver(n: &str) -> Option<(&str, &str)> {
+    lazy_static!(
+        static ref RE: Regex = Regex::new(r"^(.+?)-([0-9][^-]*(?:-[a-z][^-]*)*)\.tgz$").unwrap();
+    );
+    RE.captures(n).map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str()))
+}
+
+
+// Index a package directory (OpenBSD packages/<arch>/ or pkgsrc's All/ directory).
+pub fn pkgs(pg: &postgres::GenericConnection, sys: i32, cat: &str, arch: &str, mirror: &str) -> Result<()> {
+    let files = open::Path{path: mirror, cache: true, canbelocal: false}.dirlist()?;
+
+    // pkgsrc has the categories of all packages in pkg_summary, so we don't have to fetch each
+    // package for that.
+    let summary = if files.iter().any(|&(ref f,_)| f == "pkg_summary.gz") {
+        let path = format!("{}pkg_summary.gz", mirror);
+        let mut rd = open::Path{path: &path, cache: true, canbelocal: false}.open()?;
+        Some(read_summary(BufReader::new(archive::Archive::open_raw(&mut rd)?))?)
+    } else {
+        None
+    };
+
+    for (f, _) in files.iter().filter(|&&(_,d)| !d) {
+        let (name, ver) = match splitver(f) {
+            Some(x) => x,
+            None => { if f.ends_with(".tgz") { warn!("Unknown package: {}", f) }; continue },
+        };
+
+        let path = format!("{}{}", mirror, f);
+        let pkgcat = match summary {
+            Some(ref s) => s.get(f).cloned(),
+            None => {
+                // Don't bother fetching the metadata of packages we're going to skip anyway.
+                if pkg::exists(pg, sys, name, ver) {
+                    debug!("Package already in database: {}", f);
+                    continue;
+                }
+                match read_category(&path) {
+                    Ok(c) => c,
+                    Err(e) => { error!("Error reading metadata from {}: {}", path, e); continue },
+                }
+            },
+        };
+
+        pkg::pkg(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: pkgcat.as_ref().map(|e| &e[..]).unwrap_or(cat),
+            pkg: name,
+            ver: ver,
+            date: pkg::Date::Max,
+            arch: Some(arch),
+            digest: None,
+            file: open::Path{
+                path: &path,
+                cache: false,
+                canbelocal: false,
+            },
+        });
+    }
+    Ok(())
+}
+
+
+#[test]
+fn test_read_summary() {
+    let data = b"PKGNAME=ncdu-1.13\nPKGPATH=sysutils/ncdu\nDESCRIPTION=NCurses \xe9 Disk Usage\n\n\
+        PKGNAME=vim-8.1.0438\nFILE_NAME=vim-8.1.0438.tgz\nPKGPATH=editors/vim\n\n\
+        PKGNAME=nopath-1.0\n\n\
+        PKGNAME=last-2.0\nPKGPATH=misc/last\n";
+    let s = read_summary(&data[..]).unwrap();
+    assert_eq!(s.len(), 3);
+    assert_eq!(s.get("ncdu-1.13.tgz").map(|x| &x[..]), Some("sysutils"));
+    assert_eq!(s.get("vim-8.1.0438.tgz").map(|x| &x[..]), Some("editors"));
+    assert_eq!(s.get("last-2.0.tgz").map(|x| &x[..]), Some("misc"));
+}
+
+
+#[test]
+fn test_splitver() {
+    assert_eq!(splitver("ncdu-1.13.tgz"), Some(("ncdu", "1.13")));
+    assert_eq!(splitver("vim-8.1.0438-gtk2.tgz"), Some(("vim", "8.1.0438-gtk2")));
+    assert_eq!(splitver("py3-setuptools-39.0.1p0v0.tgz"), Some(("py3-setuptools", "39.0.1p0v0")));
+    assert_eq!(splitver("font-adobe-100dpi-1.0.3nb1.tgz"), Some(("font-adobe-100dpi", "1.0.3nb1")));
+    assert_eq!(splitver("pkg_summary.gz"), None);
+    assert_eq!(splitver("SHA256"), None);
+}
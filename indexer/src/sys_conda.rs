@@ -0,0 +1,128 @@
+use std::io::{Read,Result,Error,ErrorKind};
+use std::collections::HashMap;
+use chrono::NaiveDateTime;
+use postgres;
+use serde_json;
+use serde_json::Value;
+
+use open;
+use pkg;
+
+// Reference: https://docs.conda.io/projects/conda-build/en/latest/concepts/generating-index.html
+//
+// repodata.json has the packages of a single channel subdir (e.g. conda-forge/linux-64/) in two
+// objects, "packages" for .tar.bz2 files and "packages.conda" for .conda files, both keyed on the
+// file name.
+//
+// The same version of a package is often built several times (for different Python versions or
+// dependencies, or simply rebuilt), and each build has its own file. Only the latest build of each
+// version is indexed, preferring the .conda file over an equivalent .tar.bz2.
+
+
+#[derive(Debug,PartialEq,Eq)]
+struct Pkg {
+    file: String,
+    name: String,
+    version: String,
+    arch: Option<String>,
+    date: Option<String>,
+    // Sort key for selecting the latest build: (build_number, timestamp, is_conda)
+    order: (u64, u64, bool),
+}
+
+
+// Conda used to store the timestamp in seconds, newer packages use milliseconds.
+fn parse_timestamp(t: u64) -> String {
+    let t = if t > 100_000_000_000 { t / 1000 } else { t };
+    NaiveDateTime::from_timestamp(t as i64, 0).format("%Y-%m-%d").to_string()
+}
+
+
+fn read_repodata(rd: &mut Read) -> Result<Vec<Pkg>> {
+    let repo: Value = serde_json::from_reader(rd)?;
+    let subdir = repo["info"]["subdir"].as_str();
+
+    let mut pkgs: HashMap<(String, String), Pkg> = HashMap::new();
+    for &(key, isconda) in &[("packages", false), ("packages.conda", true)] {
+        let list = match repo[key].as_object() { Some(x) => x, None => continue };
+        for (file, p) in list {
+            let (name, version) = match (p["name"].as_str(), p["version"].as_str()) {
+                (Some(n), Some(v)) => (n, v),
+                _ => { warn!("Package without name or version: {}", file); continue },
+            };
+            let ts = p["timestamp"].as_u64();
+            let pkg = Pkg {
+                file: file.to_string(),
+                name: name.to_string(),
+                version: version.to_string(),
+                arch: p["subdir"].as_str().or(subdir).map(str::to_string),
+                date: ts.map(parse_timestamp),
+                order: (p["build_number"].as_u64().unwrap_or(0), ts.unwrap_or(0), isconda),
+            };
+            let k = (pkg.name.clone(), pkg.version.clone());
+            if pkgs.get(&k).map(|o| o.order < pkg.order).unwrap_or(true) {
+                pkgs.insert(k, pkg);
+            }
+        }
+    }
+
+    let mut pkgs: Vec<Pkg> = pkgs.into_iter().map(|(_,v)| v).collect();
+    pkgs.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    Ok(pkgs)
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, mirror: &str) -> Result<()> {
+    let path = format!("{}repodata.json", mirror);
+    let mut rd = open::Path{path: &path, cache: true, canbelocal: false}.open()?;
+    let pkgs = read_repodata(&mut rd)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("Error reading {}: {}", path, e)))?;
+    info!("Found {} packages in {}", pkgs.len(), path);
+
+    for p in pkgs {
+        let uri = format!("{}{}", mirror, p.file);
+        pkg::pkg(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: cat,
+            pkg: &p.name,
+            ver: &p.version,
+            date: match p.date { Some(ref d) => pkg::Date::Known(d), None => pkg::Date::Max },
+            arch: p.arch.as_ref().map(|e| &e[..]),
+            digest: None,
+            file: open::Path{
+                path: &uri,
+                cache: false,
+                canbelocal: false,
+            },
+        });
+    }
+    Ok(())
+}
+
+
+#[test]
+fn test_read_repodata() {
+    let data = r#"{
+        "info": {"subdir": "linux-64"},
+        "packages": {
+            "ncdu-1.13-h14c3975_0.tar.bz2": {"name": "ncdu", "version": "1.13", "build_number": 0, "timestamp": 1536000000},
+            "ncdu-1.13-h14c3975_1.tar.bz2": {"name": "ncdu", "version": "1.13", "build_number": 1, "timestamp": 1540000000000},
+            "tree-1.8.0-h516909a_0.tar.bz2": {"name": "tree", "version": "1.8.0", "build_number": 0, "subdir": "noarch"},
+            "broken.tar.bz2": {"version": "1"}
+        },
+        "packages.conda": {
+            "ncdu-1.13-h14c3975_1.conda": {"name": "ncdu", "version": "1.13", "build_number": 1, "timestamp": 1540000000000},
+            "ncdu-1.12-h14c3975_3.conda": {"name": "ncdu", "version": "1.12", "build_number": 3, "timestamp": 1530000000000}
+        }
+    }"#;
+    let pkgs = read_repodata(&mut data.as_bytes()).unwrap();
+    let s: Vec<(&str, &str, Option<&str>, Option<&str>)> = pkgs.iter().map(|p|
+        (&p.file as &str, &p.version as &str, p.arch.as_ref().map(|x| &x[..]), p.date.as_ref().map(|x| &x[..]))
+    ).collect();
+    assert_eq!(s, vec![
+        ("ncdu-1.12-h14c3975_3.conda",   "1.12",  Some("linux-64"), Some("2018-06-26")),
+        ("ncdu-1.13-h14c3975_1.conda",   "1.13",  Some("linux-64"), Some("2018-10-20")),
+        ("tree-1.8.0-h516909a_0.tar.bz2", "1.8.0", Some("noarch"),   None),
+    ]);
+}
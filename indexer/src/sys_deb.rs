@@ -1,9 +1,11 @@
-use std::io::{Result,BufReader,BufRead};
-use std::collections::HashSet;
+use std::io::{Result,Error,ErrorKind,BufReader,BufRead,Read};
+use std::collections::{HashSet,HashMap};
 use std::str;
+use std::str::FromStr;
 use postgres;
 use regex;
 use regex::bytes::Regex;
+use ring::digest;
 
 use man;
 use pkg;
@@ -53,6 +55,7 @@ struct Pkg {
     arch: Option<String>,
     version: Option<String>,
     filename: Option<String>,
+    sha256: Option<String>,
 }
 
 
@@ -85,6 +88,7 @@ fn handlepkg(pg: &postgres::GenericConnection, sys: i32, mirror: &str, manpkgs:
         ver: &version,
         date: pkg::Date::Deb,
         arch: pkg.arch.as_ref().map(|e| &e[..]),
+        digest: pkg.sha256.as_ref().and_then(|e| pkg::Checksum::new("sha256", e)),
         file: open::Path{
             path: &uri,
             cache: false,
@@ -94,20 +98,9 @@ fn handlepkg(pg: &postgres::GenericConnection, sys: i32, mirror: &str, manpkgs:
 }
 
 
-pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str, contents: Option<open::Path>, packages: open::Path) {
-    let manpkgs = match get_contents(contents) {
-        Err(e) => { error!("Can't read {}: {}", contents.unwrap().path, e); return },
-        Ok(x) => x,
-    };
-
-    let mut fd = match packages.open() {
-        Err(e) => { error!("Can't read {}: {}", packages.path, e); return },
-        Ok(x) => x,
-    };
-    let rd = match archive::Archive::open_raw(&mut fd) {
-        Err(e) => { error!("Can't read {}: {}", packages.path, e); return },
-        Ok(x) => x,
-    };
+fn read_packages(pg: &postgres::GenericConnection, sys: i32, mirror: &str, manpkgs: &HashSet<String>, packages: &open::Path) -> Result<()> {
+    let mut fd = packages.open()?;
+    let rd = archive::Archive::open_raw(&mut fd)?;
 
     let brd = BufReader::new(rd);
     let mut pkg = Pkg::default();
@@ -115,12 +108,9 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str, contents:
     let kv = Regex::new(r"^(?u:([^#-][^ :]*)\s*:\s*(.+))$").unwrap();
 
     for line in brd.split(b'\n') {
-        let line = match line {
-            Err(e) => { error!("Can't read {}: {}", packages.path, e); return },
-            Ok(x) => x,
-        };
+        let line = line?;
         if emptyline.is_match(&line) {
-            handlepkg(pg, sys, &mirror, &manpkgs, &pkg);
+            handlepkg(pg, sys, mirror, manpkgs, &pkg);
             pkg = Pkg::default();
         }
         if let Some(cap) = kv.captures(&line) {
@@ -132,9 +122,213 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str, contents:
                 "version" => pkg.version = Some(val.to_string()),
                 "architecture" => pkg.arch = Some(val.to_string()),
                 "filename" => pkg.filename = Some(val.to_string()),
+                "sha256" => pkg.sha256 = Some(val.to_string()),
                 _ => {}
             }
         }
     }
-    handlepkg(pg, sys, &mirror, &manpkgs, &pkg);
+    handlepkg(pg, sys, mirror, manpkgs, &pkg);
+    Ok(())
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str, contents: Option<open::Path>, packages: open::Path) {
+    let manpkgs = match get_contents(contents) {
+        Err(e) => { error!("Can't read {}: {}", contents.unwrap().path, e); return },
+        Ok(x) => x,
+    };
+
+    if let Err(e) = read_packages(pg, sys, mirror, &manpkgs, &packages) {
+        error!("Can't read {}: {}", packages.path, e);
+    }
+}
+
+
+#[derive(Debug,Default)]
+struct Release {
+    components: Vec<String>,
+    arches: Vec<String>,
+    // path -> (sha256, size)
+    files: HashMap<String, (String, u64)>,
+}
+
+
+// Parse an InRelease or Release file. The PGP signature of InRelease is stripped but not verified.
+fn parse_release(rd: &mut BufRead) -> Result<Release> {
+    let mut rel = Release::default();
+    let mut insig = false;
+    let mut insha = false;
+    for (i, line) in rd.lines().enumerate() {
+        let line = line?;
+        if i == 0 && line.starts_with("-----BEGIN PGP SIGNED MESSAGE") {
+            insig = true;
+            continue;
+        }
+        if insig {
+            // Armor headers ("Hash: SHA256") end with an empty line
+            insig = line != "";
+            continue;
+        }
+        if line.starts_with("-----BEGIN PGP SIGNATURE") {
+            break;
+        }
+        let line = if line.starts_with("- ") { &line[2..] } else { &line[..] }; // Dash-escaped
+
+        if line.starts_with(' ') {
+            if insha {
+                let mut f = line.split_whitespace();
+                if let (Some(hash), Some(size), Some(path)) = (f.next(), f.next(), f.next()) {
+                    rel.files.insert(path.to_string(), (hash.to_lowercase(), u64::from_str(size).unwrap_or(0)));
+                }
+            }
+            continue;
+        }
+        insha = false;
+        let mut kv = line.splitn(2, ':');
+        let (key, val) = match (kv.next(), kv.next()) { (Some(k), Some(v)) => (k, v.trim()), _ => continue };
+        match key {
+            "Components" => rel.components = val.split_whitespace().map(str::to_string).collect(),
+            "Architectures" => rel.arches = val.split_whitespace().map(str::to_string).collect(),
+            "SHA256" => insha = true,
+            _ => (),
+        }
+    }
+    Ok(rel)
+}
+
+
+// Find the smallest variant of a file listed in the Release file.
+fn best_file(rel: &Release, path: &str) -> Option<String> {
+    ["xz", "bz2", "gz", ""].iter()
+        .map(|ext| if *ext == "" { path.to_string() } else { format!("{}.{}", path, ext) })
+        .find(|p| rel.files.contains_key(p))
+}
+
+
+// Returns an error if the file doesn't match the checksum in the Release file.
+fn verify(rel: &Release, name: &str, f: &open::Path) -> Result<()> {
+    let &(ref sha, size) = rel.files.get(name).unwrap();
+    let mut rd = f.open()?;
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; 64*1024];
+    let mut len = 0u64;
+    loop {
+        let n = rd.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        len += n as u64;
+        ctx.update(&buf[..n]);
+    }
+    let hex: String = ctx.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    if len != size || &hex != sha {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Checksum mismatch for {}", f.path)));
+    }
+    Ok(())
+}
+
+
+// Read the man page packages from the Contents files for a component and arch. Newer suites have
+// per-component Contents files and a separate Contents-all for arch:all packages, older suites
+// have a single Contents file for all components in the root.
+fn suite_contents(rel: &Release, dists: &str, component: &str, arch: &str) -> Result<HashSet<String>> {
+    let mut pkgs = HashSet::new();
+    let names = [format!("{}/Contents-{}", component, arch), format!("{}/Contents-all", component), format!("Contents-{}", arch)];
+    for (i, name) in names.iter().enumerate() {
+        if i == 2 && pkgs.len() > 0 {
+            break;
+        }
+        let name = match best_file(rel, name) { Some(x) => x, None => continue };
+        let path = format!("{}{}", dists, name);
+        let f = open::Path{path: &path, cache: true, canbelocal: false};
+        verify(rel, &name, &f)?;
+        pkgs.extend(get_contents(Some(f))?);
+    }
+    Ok(pkgs)
+}
+
+
+// Index a suite (e.g. "stretch" or "xenial-updates") from the InRelease or Release file. Every
+// component and architecture (or only the given one) in the suite is indexed.
+pub fn sync_suite(pg: &postgres::GenericConnection, sys: i32, mirror: &str, suite: &str, arch: Option<&str>) -> Result<()> {
+    let dists = format!("{}dists/{}/", mirror, suite);
+    let inrelease = format!("{}InRelease", dists);
+    let release = format!("{}Release", dists);
+    let inrelease_f = open::Path{path: &inrelease, cache: true, canbelocal: false};
+    let rel = match inrelease_f.open() {
+        Ok(rd) => parse_release(&mut BufReader::new(rd))?,
+        Err(e) => {
+            debug!("Can't read {}, trying Release instead: {}", inrelease, e);
+            parse_release(&mut BufReader::new(open::Path{path: &release, cache: true, canbelocal: false}.open()?))?
+        },
+    };
+
+    let arches: Vec<&str> = match arch {
+        Some(a) => vec![a],
+        None => rel.arches.iter().map(|a| &a[..]).filter(|a| *a != "all").collect(),
+    };
+    info!("Found components {:?} and arches {:?} in {}", rel.components, arches, suite);
+
+    for component in rel.components.iter() {
+        // The component may have a path prefix (e.g. "updates/main" in debian-security), files are
+        // listed without it.
+        let base = component.rsplit('/').next().unwrap();
+        for arch in arches.iter() {
+            let name = match best_file(&rel, &format!("{}/binary-{}/Packages", base, arch)) {
+                Some(x) => x,
+                None => { warn!("No Packages file for {}/{} in {}", base, arch, suite); continue },
+            };
+            let manpkgs = suite_contents(&rel, &dists, base, arch)?;
+            if manpkgs.len() == 0 {
+                warn!("No Contents file for {}/{} in {}, indexing all packages", base, arch, suite);
+            }
+            let path = format!("{}{}", dists, name);
+            let f = open::Path{path: &path, cache: true, canbelocal: false};
+            verify(&rel, &name, &f)?;
+            read_packages(pg, sys, mirror, &manpkgs, &f)?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_release() {
+        let data = r"-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA256
+
+Origin: Debian
+Suite: stable
+Codename: stretch
+Architectures: amd64 arm64 i386
+Components: main contrib non-free
+MD5Sum:
+ 5bd4a8e0d0a8d8cbf8b3a4f5b4c3d2e1 1234 main/binary-amd64/Packages.xz
+SHA256:
+ 3957f28db16e3f28c7b34ae84f1c929c567de6970f3f1b95dac9b498dd80fe63   738242 contrib/Contents-all
+ 3E8D7B4B2E1B48F43E4A8D8A0B7B9A0A8C0D2F1E3A4B5C6D7E8F9A0B1C2D3E4F    57319 contrib/Contents-all.gz
+ 1111111111111111111111111111111111111111111111111111111111111111  8000000 main/binary-amd64/Packages.gz
+ 2222222222222222222222222222222222222222222222222222222222222222  6000000 main/binary-amd64/Packages.xz
+ 3333333333333333333333333333333333333333333333333333333333333333 40000000 main/binary-amd64/Packages
+Acquire-By-Hash: yes
+-----BEGIN PGP SIGNATURE-----
+
+iQIzBAEBCAAdFiEE
+-----END PGP SIGNATURE-----
+";
+        let rel = parse_release(&mut data.as_bytes()).unwrap();
+        assert_eq!(rel.arches, vec!["amd64", "arm64", "i386"]);
+        assert_eq!(rel.components, vec!["main", "contrib", "non-free"]);
+        assert_eq!(rel.files.len(), 5);
+        assert_eq!(rel.files.get("contrib/Contents-all.gz"),
+            Some(&("3e8d7b4b2e1b48f43e4a8d8a0b7b9a0a8c0d2f1e3a4b5c6d7e8f9a0b1c2d3e4f".to_string(), 57319)));
+
+        assert_eq!(best_file(&rel, "main/binary-amd64/Packages"), Some("main/binary-amd64/Packages.xz".to_string()));
+        assert_eq!(best_file(&rel, "contrib/Contents-all"), Some("contrib/Contents-all.gz".to_string()));
+        assert_eq!(best_file(&rel, "main/binary-i386/Packages"), None);
+    }
 }
@@ -0,0 +1,71 @@
+use std::io::Result;
+use postgres;
+
+use archive::{Archive,ArchiveEntry};
+use archread::FileList;
+use open;
+use pkg;
+use man;
+
+
+// Index the man pages in a directory on the local filesystem as a single package. This is useful
+// for systems that don't have (accessible) packages, e.g. a live system or an unpacked chroot.
+//
+// 'root' is the root of the filesystem that is being indexed, 'dir' is the directory to read (which
+// is normally inside 'root'). All paths are stored relative to the root, and absolute symlinks are
+// resolved relative to it as well.
+pub fn sync(pg: &postgres::GenericConnection, opt: pkg::PkgOpt, root: &str, dir: &str) {
+    pkg::pkg_with(pg, opt, |tr, opt, verid, stubs| {
+        let indexfunc = |paths: &[&str], ent: &mut ArchiveEntry| {
+            pkg::insert_man(tr, verid, stubs, paths, ent);
+            Ok(())
+        };
+
+        let missed = FileList::read(Archive::open_dir(root, dir)?, man::ismanpath, |ent| opt.date.update(ent), &indexfunc, |path| pkg::delete_man(tr, verid, stubs, path))?
+            .links(|src, dest| { pkg::insert_link(tr, verid, stubs, src, dest) });
+
+        if let Some(missed) = missed {
+            warn!("Some links were missed, reading directory again");
+            missed.read(Archive::open_dir(root, dir)?, indexfunc)?
+        }
+        Ok(())
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env,fs,process};
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_open_dir() {
+        let root = env::temp_dir().join(format!("manned-indexer-test-dir-{}", process::id()));
+        let man1 = root.join("usr/share/man/man1");
+        fs::create_dir_all(&man1).unwrap();
+        fs::File::create(man1.join("foo.1")).unwrap().write_all(b".TH FOO 1\n").unwrap();
+        fs::File::create(root.join("usr/share/README")).unwrap().write_all(b"Not a man page\n").unwrap();
+        symlink("foo.1", man1.join("bar.1")).unwrap();
+        symlink("/usr/share/man/man1/foo.1", man1.join("abs.1")).unwrap();
+        symlink("../man8/missing.8", man1.join("dangling.1")).unwrap();
+
+        let rootstr = root.to_str().unwrap();
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        let missed = FileList::read(Archive::open_dir(rootstr, rootstr).unwrap(), man::ismanpath, |_| (), |p, _| {
+            files.extend(p.iter().map(|x| x.to_string()));
+            Ok(())
+        }, |_| ()).unwrap().links(|src, dest| links.push((src.to_string(), dest.to_string())));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(missed.is_none());
+        assert_eq!(files, vec!["usr/share/man/man1/foo.1".to_string()]);
+        links.sort();
+        assert_eq!(links, vec![
+            ("usr/share/man/man1/abs.1".to_string(), "usr/share/man/man1/foo.1".to_string()),
+            ("usr/share/man/man1/bar.1".to_string(), "usr/share/man/man1/foo.1".to_string()),
+        ]);
+    }
+}
@@ -76,6 +76,7 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, arch: &str, mirror: &str
                     ver: ver,
                     date: pkg::Date::Desc,
                     arch: Some(arch),
+                    digest: None,
                     file: open::Path{
                         path: &path,
                         cache: false,
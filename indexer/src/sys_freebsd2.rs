@@ -34,6 +34,7 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str) -> Result<
         static ref RE_CAT  : Regex = Regex::new(r#""origin"\s*:\s*"(?u:([^ "/]+))"#).unwrap();
         static ref RE_PATH : Regex = Regex::new(r#""path"\s*:\s*"(?u:([^ "]+))""#).unwrap();
         static ref RE_ARCH : Regex = Regex::new(r#""arch"\s*:\s*"(?u:([^ "]+))""#).unwrap();
+        static ref RE_SUM  : Regex = Regex::new(r#""sum"\s*:\s*"([0-9a-fA-F]+)""#).unwrap();
     );
 
     for line in brd.split(b'\n') {
@@ -43,6 +44,7 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str) -> Result<
         let cat  = match RE_CAT .captures(&line) { None => continue, Some(c) => str::from_utf8(c.get(1).unwrap().as_bytes()).unwrap() };
         let path = match RE_PATH.captures(&line) { None => continue, Some(c) => str::from_utf8(c.get(1).unwrap().as_bytes()).unwrap() };
         let arch = match RE_ARCH.captures(&line) { None => continue, Some(c) => str::from_utf8(c.get(1).unwrap().as_bytes()).unwrap() };
+        let sum  = RE_SUM.captures(&line).map(|c| str::from_utf8(c.get(1).unwrap().as_bytes()).unwrap());
         let uri = format!("{}{}", mirror, path);
         pkg::pkg(pg, pkg::PkgOpt{
             force: false,
@@ -52,6 +54,7 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str) -> Result<
             ver: ver,
             date: pkg::Date::Max,
             arch: Some(arch),
+            digest: sum.and_then(|s| pkg::Checksum::new("sha256", s)),
             file: open::Path{
                 path: &uri,
                 cache: false,
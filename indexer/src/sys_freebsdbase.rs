@@ -0,0 +1,78 @@
+use std::io::{BufRead,BufReader,Result};
+use postgres;
+
+use open;
+use pkg;
+
+
+// Distribution sets that contain man pages.
+const SETS: &'static [&'static str] = &["base", "lib32"];
+
+
+// Reads the MANIFEST of a release directory, which lists the available dist sets, one per line:
+// "<set>.txz\t<sha256>\t<nfiles>\t<name>\t<description>\t<on/off>". Returns the set name, file
+// name and sha256 of each set in SETS.
+fn read_manifest<R: BufRead>(brd: R) -> Result<Vec<(String, String, Option<String>)>> {
+    let mut sets = Vec::new();
+    for line in brd.lines() {
+        let line = line?;
+        let mut cols = line.split('\t');
+        let file = match cols.next() { Some(x) => x, None => continue };
+        let set = file.trim_right_matches(".txz");
+        if set == file || !SETS.contains(&set) {
+            continue;
+        }
+        let sha256 = cols.next().filter(|h| !h.is_empty()).map(str::to_string);
+        sets.push((set.to_string(), file.to_string(), sha256));
+    }
+    Ok(sets)
+}
+
+
+// Sync the base system of a FreeBSD >= 9.0 release.
+//
+// Each interesting set in the MANIFEST of the release directory (e.g. releases/amd64/11.1-RELEASE/)
+// is indexed as a separate package in the "base" category and verified against the checksum in
+// the MANIFEST. Neither the MANIFEST nor the release directory has a trustworthy date, so the
+// release date is the most recent file modification time within each set.
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, ver: &str, arch: &str, mirror: &str) -> Result<()> {
+    let path = format!("{}MANIFEST", mirror);
+    let brd = BufReader::new(open::Path{path: &path, cache: true, canbelocal: false}.open()?);
+    for (set, file, sha256) in read_manifest(brd)? {
+        let uri = format!("{}{}", mirror, file);
+        pkg::pkg(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: "base",
+            pkg: &set,
+            ver: ver,
+            date: pkg::Date::Max,
+            arch: Some(arch),
+            digest: sha256.as_ref().and_then(|h| pkg::Checksum::new("sha256", h)),
+            file: open::Path{
+                path: &uri,
+                cache: false,
+                canbelocal: false,
+            },
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_read_manifest() {
+        let f = File::open("tests/freebsd-MANIFEST").unwrap();
+        let sets = read_manifest(BufReader::new(f)).unwrap();
+        let s = |x: &str| x.to_string();
+        assert_eq!(sets, vec![
+            (s("base"), s("base.txz"), Some(s("8ab8aeb0c3a8fe13d2f0b1fdeb62ae2bc2a4cd4c9fd0e4a4e9b2ae16b5e7b7c1"))),
+            (s("lib32"), s("lib32.txz"), None),
+        ]);
+    }
+}
@@ -0,0 +1,117 @@
+use std::str::FromStr;
+use std::io::{BufRead,BufReader,Result};
+use chrono::NaiveDateTime;
+use regex::Regex;
+use postgres;
+
+use archive::Archive;
+use open;
+use pkg;
+
+// Reference: https://wiki.gentoo.org/wiki/Binary_package_guide
+// and the GLEP 78 for the .gpkg.tar format.
+
+
+#[derive(Default)]
+struct Meta {
+    cpv: Option<String>,
+    category: Option<String>,
+    date: Option<i64>,
+    path: Option<String>,
+}
+
+
+// Split a "<category>/<package>-<version>" string into its components.
+fn splitcpv(cpv: &str) -> Option<(&str, &str, &str)> {
+    lazy_static!(
+        static ref RE: Regex = Regex::new(r"(?x)^
+            ([^/]+) /
+            (.+?) -
+            ([0-9]+(?:\.[0-9]+)*[a-z]?(?:_(?:alpha|beta|pre|rc|p)[0-9]*)*(?:-r[0-9]+)?)
+        $").unwrap();
+    );
+    RE.captures(cpv).map(|c| (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str(), c.get(3).unwrap().as_str()))
+}
+
+
+fn handlepkg(pg: &postgres::GenericConnection, sys: i32, mirror: &str, arch: Option<&str>, m: &Meta) {
+    // The first block in the index is a header without a CPV
+    let cpv = match m.cpv { Some(ref x) => x, None => return };
+    let (cat, name, ver) = match splitcpv(cpv) { Some(x) => x, None => { warn!("Invalid CPV: {}", cpv); return } };
+    let cat = m.category.as_ref().map(|e| &e[..]).unwrap_or(cat);
+    let date = match m.date { Some(x) => x, None => { error!("Package {} has no build time", cpv); return } };
+    let date = NaiveDateTime::from_timestamp(date, 0).format("%Y-%m-%d").to_string();
+
+    // PATH is only present for .gpkg.tar files and multi-instance binhosts, otherwise it's the
+    // traditional <CPV>.tbz2.
+    let path = match m.path {
+        Some(ref p) => format!("{}{}", mirror, p),
+        None => format!("{}{}.tbz2", mirror, cpv),
+    };
+
+    pkg::pkg(pg, pkg::PkgOpt{
+        force: false,
+        sys: sys,
+        cat: cat,
+        pkg: name,
+        ver: ver,
+        date: pkg::Date::Known(&date),
+        arch: arch,
+        digest: None,
+        file: open::Path{
+            path: &path,
+            cache: false,
+            canbelocal: false,
+        },
+    });
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str) -> Result<()> {
+    info!("Reading packages from {}", mirror);
+
+    let path = format!("{}Packages", mirror);
+    let mut rd = open::Path{path: &path, cache: true, canbelocal: false}.open()?;
+    let brd = BufReader::new(Archive::open_raw(&mut rd)?);
+
+    // The ARCH is only given in the header, it applies to all packages.
+    let mut arch = None;
+    let mut header = true;
+    let mut m = Meta::default();
+    for line in brd.lines() {
+        let line = line?;
+        if line.trim() == "" {
+            handlepkg(pg, sys, mirror, arch.as_ref().map(|e: &String| &e[..]), &m);
+            m = Meta::default();
+            header = false;
+            continue;
+        }
+        let mut kv = line.splitn(2, ": ");
+        let (key, val) = match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => (k, v.trim()),
+            _ => continue,
+        };
+        match key {
+            "ARCH"       => if header { arch = Some(val.to_string()) },
+            "CPV"        => m.cpv      = Some(val.to_string()),
+            "CATEGORY"   => m.category = Some(val.to_string()),
+            "BUILD_TIME" => m.date     = i64::from_str(val).ok(),
+            "PATH"       => m.path     = Some(val.to_string()),
+            _ => {},
+        }
+    }
+    handlepkg(pg, sys, mirror, arch.as_ref().map(|e: &String| &e[..]), &m);
+    Ok(())
+}
+
+
+#[test]
+fn test_splitcpv() {
+    assert_eq!(splitcpv("app-misc/ncdu-1.13"), Some(("app-misc", "ncdu", "1.13")));
+    assert_eq!(splitcpv("sys-apps/man-pages-4.16-r1"), Some(("sys-apps", "man-pages", "4.16-r1")));
+    assert_eq!(splitcpv("media-fonts/font-adobe-100dpi-1.0.3-r2"), Some(("media-fonts", "font-adobe-100dpi", "1.0.3-r2")));
+    assert_eq!(splitcpv("dev-lang/python-3.6.5_p1"), Some(("dev-lang", "python", "3.6.5_p1")));
+    assert_eq!(splitcpv("sys-libs/glibc-2.27_rc1_p3-r4"), Some(("sys-libs", "glibc", "2.27_rc1_p3-r4")));
+    assert_eq!(splitcpv("app-misc/ncdu"), None);
+    assert_eq!(splitcpv("ncdu-1.13"), None);
+}
@@ -0,0 +1,113 @@
+use std::io::Result;
+use regex::Regex;
+use url::percent_encoding::percent_decode;
+use postgres;
+
+use archive::{walk,Archive,FileType};
+use open;
+use pkg;
+
+
+struct PkgInfo<'a> {
+    name: &'a str,
+    ver: String,
+    arch: Option<&'a str>,
+    date: pkg::Date<'static>,
+}
+
+
+// Get the package metadata from a file name, returns None if it doesn't look like a package.
+fn pkginfo(name: &str) -> Option<PkgInfo> {
+    lazy_static!(
+        // <name>_<version>_<arch>.deb, the version may have a %3a for the epoch.
+        static ref DEB: Regex = Regex::new(r"^([^_]+)_([^_]+)_([^_]+)\.deb$").unwrap();
+        // <name>-<version>-<release>.<arch>.rpm
+        static ref RPM: Regex = Regex::new(r"^(.+)-([^-]+-[^-]+)\.([^\.-]+)\.rpm$").unwrap();
+        // <name>-<version>.tgz, as used by Slackware and FreeBSD.
+        static ref TGZ: Regex = Regex::new(r"^(.+?)-([0-9].*)\.t[bgx]z$").unwrap();
+    );
+
+    if let Some(c) = DEB.captures(name) {
+        Some(PkgInfo {
+            name: c.get(1).unwrap().as_str(),
+            ver: percent_decode(c[2].as_bytes()).decode_utf8_lossy().into_owned(),
+            arch: Some(c.get(3).unwrap().as_str()),
+            date: pkg::Date::Deb,
+        })
+    } else if let Some(c) = RPM.captures(name) {
+        if &c[3] == "src" {
+            return None;
+        }
+        Some(PkgInfo {
+            name: c.get(1).unwrap().as_str(),
+            ver: c[2].to_string(),
+            arch: Some(c.get(3).unwrap().as_str()),
+            date: pkg::Date::Max,
+        })
+    } else if let Some(c) = TGZ.captures(name) {
+        Some(PkgInfo {
+            name: c.get(1).unwrap().as_str(),
+            ver: c[2].to_string(),
+            arch: None,
+            date: pkg::Date::Max,
+        })
+    } else {
+        None
+    }
+}
+
+
+// Walk through an ISO image (or any other archive format supported by libarchive, really) and
+// index all packages in it. The directory in which a package is found is used as its category,
+// which works out alright for the old Debian, Slackware and Red Hat layouts.
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, path: &str) -> Result<()> {
+    let file = open::Path{path: path, cache: false, canbelocal: true};
+    let mut rd = file.open()?;
+    let ent = Archive::open_archive(&mut rd)?;
+
+    walk(ent, |e| {
+        if e.filetype() != FileType::File {
+            return Ok(true);
+        }
+        let fpath = match e.path() { Some(x) => x.to_string(), None => return Ok(true) };
+        let (dir, fname) = match fpath.rfind('/') {
+            Some(i) => (&fpath[..i], &fpath[i+1..]),
+            None => ("", &fpath[..]),
+        };
+        let info = match pkginfo(fname) { Some(x) => x, None => return Ok(true) };
+        let cat = match dir.rfind('/') {
+            Some(i) => &dir[i+1..],
+            None if dir == "" => "iso",
+            None => dir,
+        };
+
+        pkg::pkg_in(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: cat,
+            pkg: info.name,
+            ver: &info.ver,
+            date: info.date,
+            arch: info.arch,
+            digest: None,
+            file: file,
+        }, &fpath, e);
+        Ok(true)
+    })
+}
+
+
+#[test]
+fn test_pkginfo() {
+    let t = |f, n, v, a: Option<&str>| {
+        let i = pkginfo(f).unwrap();
+        assert_eq!((i.name, &i.ver as &str, i.arch), (n, v, a));
+    };
+    t("bash_1.14.7-1_i386.deb", "bash", "1.14.7-1", Some("i386"));
+    t("dpkg_1%3a1.18.24_amd64.deb", "dpkg", "1:1.18.24", Some("amd64"));
+    t("bash-1.14.7-22.i386.rpm", "bash", "1.14.7-22", Some("i386"));
+    t("bash-1.14.7.tgz", "bash", "1.14.7", None);
+    t("man-pages-1.5.txz", "man-pages", "1.5", None);
+    assert!(pkginfo("bash-1.14.7-22.src.rpm").is_none());
+    assert!(pkginfo("README.txt").is_none());
+}
@@ -0,0 +1,183 @@
+use std::io::{Read,Result};
+use std::fs::{File,read_dir};
+use std::collections::BTreeMap;
+use chrono::NaiveDateTime;
+use postgres;
+
+use archive::Archive;
+use archread::FileList;
+use nar::NarEntry;
+use open;
+use pkg;
+use man;
+
+// Reads a Nix binary cache from a local directory (or file:// URL). The cache has a
+// <hash>.narinfo file for each store path, pointing to a (compressed) NAR file:
+//
+//   StorePath: /nix/store/<hash>-<name>
+//   URL: nar/<filehash>.nar.xz
+//   Compression: xz
+//
+// A package is often split into several outputs, each with its own store path. The "out" output
+// has the plain name, the others have the output name as suffix (e.g. "coreutils-9.1-man"). All
+// outputs with the same name and version are indexed as a single package.
+//
+// NAR files don't have timestamps, the modification time of the .narinfo is used as release date.
+
+
+// Default output names, see multiple-outputs.sh in nixpkgs.
+const OUTPUTS: &'static [&'static str] = &["bin", "dev", "devdoc", "devman", "doc", "info", "lib", "man", "static"];
+
+
+#[derive(Debug,PartialEq,Eq)]
+struct NarInfo {
+    store_path: String,
+    url: String,
+}
+
+
+fn parse_narinfo(data: &str) -> Option<NarInfo> {
+    let mut store_path = None;
+    let mut url = None;
+    for line in data.lines() {
+        let mut kv = line.splitn(2, ": ");
+        match (kv.next(), kv.next()) {
+            (Some("StorePath"), Some(v)) => store_path = Some(v.to_string()),
+            (Some("URL"), Some(v)) => url = Some(v.to_string()),
+            _ => (),
+        }
+    }
+    match (store_path, url) {
+        (Some(s), Some(u)) => Some(NarInfo { store_path: s, url: u }),
+        _ => None,
+    }
+}
+
+
+// Split a store path into package name and version. Same logic as Nix' parseDrvName(): The version
+// starts at the first dash that is not followed by a letter.
+fn parse_name(store_path: &str) -> Option<(&str, &str)> {
+    let base = store_path.rsplit('/').next().unwrap();
+    // <32 character hash>-<name>
+    if base.len() < 34 || !base.is_char_boundary(33) || &base[32..33] != "-" {
+        return None;
+    }
+    let mut name = &base[33..];
+    for o in OUTPUTS {
+        if name.ends_with(o) && name[..name.len()-o.len()].ends_with('-') {
+            name = &name[..name.len()-o.len()-1];
+            break;
+        }
+    }
+    name.char_indices()
+        .find(|&(i, c)| c == '-' && name[i+1..].chars().next().map(|c| !c.is_alphabetic()) == Some(true))
+        .map(|(i, _)| (&name[..i], &name[i+1..]))
+}
+
+
+fn index_nar(tr: &postgres::GenericConnection, verid: i32, stubs: &pkg::Stubs, path: &str) -> Result<()> {
+    let indexfunc = |paths: &[&str], ent: &mut NarEntry| {
+        pkg::insert_man(tr, verid, stubs, paths, ent);
+        Ok(())
+    };
+
+    let missed = {
+        let mut f = File::open(path)?;
+        let mut rd = Archive::open_raw(&mut f)?;
+        FileList::read(NarEntry::open(&mut rd)?, man::ismanpath, |_| (), &indexfunc, |path| pkg::delete_man(tr, verid, stubs, path))?
+            .links(|src, dest| { pkg::insert_link(tr, verid, stubs, src, dest) })
+    };
+
+    if let Some(missed) = missed {
+        warn!("Some links were missed, reading NAR again");
+        let mut f = File::open(path)?;
+        let mut rd = Archive::open_raw(&mut f)?;
+        missed.read(NarEntry::open(&mut rd)?, indexfunc)?
+    }
+    Ok(())
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, arch: Option<&str>, cache: &str) -> Result<()> {
+    let dir = cache.trim_left_matches("file://").trim_right_matches('/');
+
+    // (name, version) -> (date, [nar])
+    let mut pkgs: BTreeMap<(String, String), (i64, Vec<String>)> = BTreeMap::new();
+    for f in read_dir(dir)? {
+        let f = f?.path();
+        if !f.extension().map(|e| e == "narinfo").unwrap_or(false) {
+            continue;
+        }
+        let path = match f.to_str() { Some(x) => x, None => continue };
+        let file = open::Path{path: path, cache: false, canbelocal: true};
+
+        let mut data = String::new();
+        file.open()?.read_to_string(&mut data)?;
+        let info = match parse_narinfo(&data) { Some(x) => x, None => { warn!("Invalid narinfo: {}", path); continue } };
+        let (name, ver) = match parse_name(&info.store_path) {
+            Some(x) => x,
+            None => { debug!("Ignoring store path without version: {}", info.store_path); continue },
+        };
+        let mtime = file.mtime()?.unwrap_or(0);
+
+        let ent = pkgs.entry((name.to_string(), ver.to_string())).or_insert((0, Vec::new()));
+        ent.0 = if mtime > ent.0 { mtime } else { ent.0 };
+        ent.1.push(format!("{}/{}", dir, info.url));
+    }
+
+    for ((name, ver), (mtime, nars)) in pkgs {
+        let date = NaiveDateTime::from_timestamp(mtime, 0).format("%Y-%m-%d").to_string();
+        pkg::pkg_with(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: cat,
+            pkg: &name,
+            ver: &ver,
+            date: pkg::Date::Known(&date),
+            arch: arch,
+            digest: None,
+            file: open::Path{
+                path: &nars[0],
+                cache: false,
+                canbelocal: true,
+            },
+        }, |tr, _, verid, stubs| {
+            for nar in &nars {
+                index_nar(tr, verid, stubs, nar)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_narinfo() {
+        let info = parse_narinfo("StorePath: /nix/store/00bgd045z0d4icpbc2yyz4gx48ak44la-net-tools-1.60_p20170221182432\n\
+            URL: nar/1094wph9z4nwlgvsd53abfz8i117ykiv5dwnq9nnhz846s7xqd7d.nar.xz\n\
+            Compression: xz\n\
+            FileHash: sha256:1094wph9z4nwlgvsd53abfz8i117ykiv5dwnq9nnhz846s7xqd7d\n\
+            FileSize: 114980\n").unwrap();
+        assert_eq!(info, NarInfo {
+            store_path: "/nix/store/00bgd045z0d4icpbc2yyz4gx48ak44la-net-tools-1.60_p20170221182432".to_string(),
+            url: "nar/1094wph9z4nwlgvsd53abfz8i117ykiv5dwnq9nnhz846s7xqd7d.nar.xz".to_string(),
+        });
+        assert_eq!(parse_narinfo("Compression: xz\n"), None);
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name("/nix/store/00bgd045z0d4icpbc2yyz4gx48ak44la-net-tools-1.60_p20170221182432"), Some(("net-tools", "1.60_p20170221182432")));
+        assert_eq!(parse_name("/nix/store/5b3bbh3ypxa5n0ia2rk6c1q3b2z3jbgh-coreutils-9.1-man"), Some(("coreutils", "9.1")));
+        assert_eq!(parse_name("/nix/store/5b3bbh3ypxa5n0ia2rk6c1q3b2z3jbgh-man-pages-6.01"), Some(("man-pages", "6.01")));
+        assert_eq!(parse_name("/nix/store/5b3bbh3ypxa5n0ia2rk6c1q3b2z3jbgh-gnu-config-2023-01-21"), Some(("gnu-config", "2023-01-21")));
+        assert_eq!(parse_name("/nix/store/5b3bbh3ypxa5n0ia2rk6c1q3b2z3jbgh-hello-2.12.1-doc"), Some(("hello", "2.12.1")));
+        assert_eq!(parse_name("/nix/store/5b3bbh3ypxa5n0ia2rk6c1q3b2z3jbgh-etc-hosts"), None);
+        assert_eq!(parse_name("/nix/store/short-name-1.0"), None);
+    }
+}
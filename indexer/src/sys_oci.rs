@@ -0,0 +1,294 @@
+use std::io::{Read,Result,Error,ErrorKind};
+use std::fs::{File,metadata};
+use postgres;
+use serde_json;
+use serde_json::Value;
+
+use archive::{Archive,ArchiveEntry};
+use archread::{FileList,LayerList};
+use open;
+use pkg;
+use man;
+
+// Reference: https://github.com/opencontainers/image-spec/blob/master/image-layout.md
+// and https://github.com/moby/moby/blob/master/image/spec/v1.2.md for the "docker save" format.
+//
+// Both an OCI image layout directory and a "docker save" tarball are supported. Each image tag is
+// indexed as a separate package with the repository as name and the tag as version.
+
+
+enum Image<'a> {
+    Dir(&'a str),
+    Tar(&'a str),
+}
+
+
+#[derive(Debug,PartialEq,Eq)]
+struct ImageInfo {
+    name: String,
+    tag: String,
+    arch: Option<String>,
+    date: Option<String>,
+    layers: Vec<String>,
+}
+
+
+impl<'a> Image<'a> {
+    fn open(path: &str) -> Result<Image> {
+        Ok(if metadata(path)?.is_dir() { Image::Dir(path) } else { Image::Tar(path) })
+    }
+
+    fn path(&self) -> &str {
+        match *self { Image::Dir(p) | Image::Tar(p) => p }
+    }
+
+    // Call the callback with a reader for the given file in the image. The file name is relative to
+    // the image directory or the root of the tarball.
+    fn with_file<F,T>(&self, name: &str, cb: F) -> Result<T>
+        where F: FnOnce(&mut Read) -> Result<T>
+    {
+        match *self {
+            Image::Dir(dir) => {
+                let mut f = File::open(format!("{}/{}", dir, name))?;
+                cb(&mut f)
+            },
+            Image::Tar(path) => {
+                let mut f = File::open(path)?;
+                let mut ent = Archive::open_archive(&mut f)?;
+                while let Some(mut e) = ent {
+                    if e.path() == Some(name) {
+                        return cb(&mut e);
+                    }
+                    ent = e.next()?;
+                }
+                Err(Error::new(ErrorKind::NotFound, format!("File not found in image: {}", name)))
+            },
+        }
+    }
+
+    fn json(&self, name: &str) -> Result<Value> {
+        self.with_file(name, |rd| Ok(serde_json::from_reader(rd)?))
+    }
+
+    fn has_file(&self, name: &str) -> bool {
+        self.with_file(name, |_| Ok(())).is_ok()
+    }
+
+    fn with_layer<F,T>(&self, name: &str, cb: F) -> Result<T>
+        where F: FnOnce(Option<ArchiveEntry>) -> Result<T>
+    {
+        self.with_file(name, |rd| cb(Archive::open_archive(rd)?))
+    }
+}
+
+
+fn blob_path(digest: &str) -> Option<String> {
+    let mut it = digest.splitn(2, ':');
+    match (it.next(), it.next()) {
+        (Some(alg), Some(hash)) if !alg.contains('/') && !hash.contains('/') => Some(format!("blobs/{}/{}", alg, hash)),
+        _ => None,
+    }
+}
+
+
+// Read the architecture and creation date from an image config
+fn read_config(img: &Image, name: &str) -> Result<(Option<String>, Option<String>)> {
+    let conf = img.json(name)?;
+    let arch = conf["architecture"].as_str().map(str::to_string);
+    // RFC 3339, only the date part is used
+    let date = conf["created"].as_str().filter(|d| d.len() >= 10 && d.is_char_boundary(10)).map(|d| d[..10].to_string());
+    Ok((arch, date))
+}
+
+
+// Split "<repository>:<tag>" into its components; The repository may have a port number.
+fn split_tag(tag: &str) -> (&str, &str) {
+    match tag.rfind(':') {
+        Some(i) if !tag[i+1..].contains('/') => (&tag[..i], &tag[i+1..]),
+        _ => (tag, "latest"),
+    }
+}
+
+
+// "docker save" format: manifest.json with an array of images
+fn read_docker(img: &Image) -> Result<Vec<ImageInfo>> {
+    let mut res = Vec::new();
+    let manifest = img.json("manifest.json")?;
+    for m in manifest.as_array().map(|a| &a[..]).unwrap_or(&[]) {
+        let layers: Vec<String> = m["Layers"].as_array().map(|a| &a[..]).unwrap_or(&[])
+            .iter().filter_map(|l| l.as_str().map(str::to_string)).collect();
+        let (arch, date) = match m["Config"].as_str() {
+            Some(c) => read_config(img, c)?,
+            None => (None, None),
+        };
+        for t in m["RepoTags"].as_array().map(|a| &a[..]).unwrap_or(&[]).iter().filter_map(|t| t.as_str()) {
+            let (name, tag) = split_tag(t);
+            res.push(ImageInfo {
+                name: name.to_string(),
+                tag: tag.to_string(),
+                arch: arch.clone(),
+                date: date.clone(),
+                layers: layers.clone(),
+            });
+        }
+    }
+    Ok(res)
+}
+
+
+// OCI image layout: index.json with a list of manifests, which reference the config and layers.
+// Nested image indexes (multi-platform images) are not supported.
+fn read_oci(img: &Image, name: &str) -> Result<Vec<ImageInfo>> {
+    let mut res = Vec::new();
+    let index = img.json("index.json")?;
+    for m in index["manifests"].as_array().map(|a| &a[..]).unwrap_or(&[]) {
+        let tag = match m["annotations"]["org.opencontainers.image.ref.name"].as_str() {
+            Some(t) => t,
+            None => { warn!("Untagged image in {}", img.path()); continue },
+        };
+        if m["mediaType"].as_str() == Some("application/vnd.oci.image.index.v1+json") {
+            warn!("Nested image index in {}: {}", img.path(), tag);
+            continue;
+        }
+        let manifest = match m["digest"].as_str().and_then(blob_path) {
+            Some(p) => img.json(&p)?,
+            None => { warn!("Invalid digest for {}", tag); continue },
+        };
+        let layers = manifest["layers"].as_array().map(|a| &a[..]).unwrap_or(&[])
+            .iter().filter_map(|l| l["digest"].as_str().and_then(blob_path)).collect();
+        let (arch, date) = match manifest["config"]["digest"].as_str().and_then(blob_path) {
+            Some(c) => read_config(img, &c)?,
+            None => (None, None),
+        };
+        res.push(ImageInfo {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            arch: arch,
+            date: date,
+            layers: layers,
+        });
+    }
+    Ok(res)
+}
+
+
+// Read all man pages from an image, see archread::LayerList for the details. The callbacks are the
+// same as for archread::FileList::read() and links().
+fn read_image<E,F,R,G>(img: &Image, info: &ImageInfo, mut entry_cb: E, mut file_cb: F, mut replace_cb: R, link_cb: G) -> Result<()>
+    where E: FnMut(&ArchiveEntry), F: FnMut(&[&str], &mut ArchiveEntry) -> Result<()>, R: FnMut(&str), G: FnMut(&str, &str)
+{
+    let mut ll = LayerList::new();
+    for (i, l) in info.layers.iter().enumerate() {
+        img.with_layer(l, |e| ll.add(i, e))?;
+    }
+
+    let mut fl = FileList::new();
+    for (i, l) in info.layers.iter().enumerate() {
+        img.with_layer(l, |e| fl.add(e, |p| ll.visible(i, p), man::ismanpath, &mut entry_cb, &mut file_cb, &mut replace_cb))?;
+    }
+
+    if let Some(mut missed) = fl.links(link_cb) {
+        warn!("Some links were missed, reading image again");
+        for (i, l) in info.layers.iter().enumerate() {
+            img.with_layer(l, |e| missed.read_from(e, |p| ll.visible(i, p), &mut file_cb))?;
+        }
+    }
+    Ok(())
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, name: Option<&str>, path: &str) -> Result<()> {
+    let img = Image::open(path)?;
+
+    // "docker save" from Docker >= 25 is an OCI layout that also has a manifest.json, prefer the
+    // latter because it has the repository name.
+    let images = if img.has_file("manifest.json") {
+        read_docker(&img)?
+    } else {
+        let name = match name { Some(n) => n, None => return Err(Error::new(ErrorKind::Other, "Image name required for OCI image layouts")) };
+        read_oci(&img, name)?
+    };
+
+    for info in images {
+        let date = match info.date {
+            Some(ref d) => pkg::Date::Known(d),
+            None => pkg::Date::Max,
+        };
+        let pkgname = name.unwrap_or(&info.name);
+        pkg::pkg_with(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: cat,
+            pkg: pkgname,
+            ver: &info.tag,
+            date: date,
+            arch: info.arch.as_ref().map(|e| &e[..]),
+            digest: None,
+            file: open::Path{
+                path: path,
+                cache: false,
+                canbelocal: true,
+            },
+        }, |tr, opt, verid, stubs| {
+            read_image(&img, &info,
+                |ent| opt.date.update(ent),
+                |paths, ent| { pkg::insert_man(tr, verid, stubs, paths, ent); Ok(()) },
+                |path| pkg::delete_man(tr, verid, stubs, path),
+                |src, dest| pkg::insert_link(tr, verid, stubs, src, dest)
+            )
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_docker_save() {
+        let img = Image::open("tests/ociimage.tar").unwrap();
+        let info = read_docker(&img).unwrap();
+        assert_eq!(info.len(), 2);
+        assert_eq!(info[0], ImageInfo {
+            name: "manned/test".to_string(),
+            tag: "1.0".to_string(),
+            arch: Some("amd64".to_string()),
+            date: Some("2018-04-20".to_string()),
+            layers: vec!["layer1/layer.tar".to_string(), "layer2/layer.tar".to_string()],
+        });
+        assert_eq!(info[1].tag, "latest");
+
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        read_image(&img, &info[0],
+            |_| (),
+            |p, e| {
+                let mut cont = String::new();
+                e.read_to_string(&mut cont).unwrap();
+                files.extend(p.iter().map(|x| (x.to_string(), cont.clone())));
+                Ok(())
+            },
+            |_| (),
+            |src, dest| links.push((src.to_string(), dest.to_string()))
+        ).unwrap();
+        files.sort();
+        assert_eq!(files, vec![
+            ("usr/share/man/man1/bar.1".to_string(), "New bar man page\n".to_string()),
+            ("usr/share/man/man1/foo.1".to_string(), "Foo man page\n".to_string()),
+            ("usr/share/man/man5/visible.5".to_string(), "Visible man page\n".to_string()),
+        ]);
+        assert_eq!(links, vec![
+            ("usr/share/man/man1/barlink.1".to_string(), "usr/share/man/man1/bar.1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_split_tag() {
+        assert_eq!(split_tag("debian:stretch"), ("debian", "stretch"));
+        assert_eq!(split_tag("localhost:5000/debian"), ("localhost:5000/debian", "latest"));
+        assert_eq!(split_tag("localhost:5000/debian:9"), ("localhost:5000/debian", "9"));
+    }
+}
@@ -1,5 +1,5 @@
-use std::collections::HashSet;
-use std::io::BufReader;
+use std::collections::{HashMap,HashSet};
+use std::io::{BufReader,Read};
 use std::str::FromStr;
 use std::error::Error;
 use std::fmt;
@@ -15,15 +15,16 @@ use man;
 
 
 // Ugh, quick-xml's Error type does not implement Error.
+// (Also used by sys_void)
 #[derive(Debug)]
-struct XmlError(String);
+pub struct XmlError(pub String);
 impl fmt::Display for XmlError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.0) }
 }
 impl Error for XmlError {
     fn description(&self) -> &str { self.0.as_ref() }
 }
-fn to_err(e: xml::Error) -> XmlError {
+pub fn to_err(e: xml::Error) -> XmlError {
     XmlError(format!("{}", e))
 }
 
@@ -47,20 +48,30 @@ struct PkgInfo {
     ver: Option<String>,
     date: Option<i64>,
     path: Option<String>,
+    keyword: Option<String>,
+    // (type, hex)
+    checksum: Option<(String, String)>,
     hasman: bool,
 }
 
 
-// Shared function to read primary.xml.gz and filelists.xml.gz. Runs the callback for each package
-// with the info that was found.
-fn readpkgs<F>(url: String, mut cb: F) -> Result<(),Box<Error>>
+// Shared function to read primary.xml.gz, filelists.xml.gz and susedata.xml.gz. Runs the callback
+// for each package with the info that was found.
+fn readpkgs<F>(url: String, cb: F) -> Result<(),Box<Error>>
     where F: FnMut(PkgInfo)
 {
     debug!("Reading {}", url);
     let mut fd = open::Path{path: &url, cache: true, canbelocal: false}.open()?;
+    readpkgs_rd(&mut fd, cb)
+}
+
+
+fn readpkgs_rd<F>(fd: &mut Read, mut cb: F) -> Result<(),Box<Error>>
+    where F: FnMut(PkgInfo)
+{
     let mut xml = xml::Reader::from_reader(
         BufReader::new(
-            archive::Archive::open_raw(&mut fd)?
+            archive::Archive::open_raw(fd)?
         )
     );
     xml.trim_text(true);
@@ -83,7 +94,12 @@ fn readpkgs<F>(url: String, mut cb: F) -> Result<(),Box<Error>>
                 match e.name() {
                     b"name" |
                     b"file" |
+                    b"keyword" |
                     b"arch"     => savestr  = true,
+                    b"checksum" => {
+                        savestr = true;
+                        pkg.checksum = xml_getattr(e, "type").ok().map(|t| (t, String::new()));
+                    },
                     b"version"  => pkg.ver  = Some(format!("{}-{}", xml_getattr(e, "ver")?, xml_getattr(e, "rel")?)),
                     b"location" => pkg.path = Some(xml_getattr(e, "href")?),
                     b"time"     => pkg.date = Some(i64::from_str(&xml_getattr(e, "build")?)?),
@@ -106,6 +122,14 @@ fn readpkgs<F>(url: String, mut cb: F) -> Result<(),Box<Error>>
                     b"name" => pkg.name = Some(saved.take().unwrap()),
                     b"arch" => pkg.arch = Some(saved.take().unwrap()),
                     b"file" => pkg.hasman = pkg.hasman || man::ismanpath(&saved.take().unwrap()),
+                    // openSUSE mostly uses keywords for the support level, those don't make for a
+                    // useful category.
+                    b"keyword" => if let Some(kw) = saved.take() {
+                        if pkg.keyword.is_none() && !kw.starts_with("support_") {
+                            pkg.keyword = Some(kw);
+                        }
+                    },
+                    b"checksum" => if let Some((_, ref mut hex)) = pkg.checksum { *hex = saved.take().unwrap_or_default() },
                     b"package" => {
                         if pkg.arch != arch_src {
                             cb(pkg);
@@ -124,19 +148,35 @@ fn readpkgs<F>(url: String, mut cb: F) -> Result<(),Box<Error>>
 }
 
 
-// Reads repomd.xml and returns the path to the primary.xml.gz and filelists.xml.gz
-fn repomd(url: String) -> Result<(String,String),Box<Error>> {
+#[derive(Default)]
+struct RepoMd {
+    primary: String,
+    filelists: String,
+    susedata: Option<String>,
+}
+
+
+fn repomd(url: String) -> Result<RepoMd,Box<Error>> {
     debug!("Reading {}", url);
     let mut fd = open::Path{path: &url, cache: true, canbelocal: false}.open()?;
+    repomd_rd(&mut fd)
+}
+
+
+// Reads repomd.xml and returns the paths to the primary.xml.gz, filelists.xml.gz and (if present)
+// susedata.xml.gz.
+// Newer repositories also provide zchunk variants of these files (type="primary_zck" etc), which
+// libarchive can't read. These are ignored, both by type and by file extension. The zstd-compressed
+// files used by openSUSE are handled by libarchive (>= 3.3.3) like any other compression.
+fn repomd_rd(fd: &mut Read) -> Result<RepoMd,Box<Error>> {
     let mut xml = xml::Reader::from_reader(
         BufReader::new(
-            archive::Archive::open_raw(&mut fd)?
+            archive::Archive::open_raw(fd)?
         )
     );
     xml.trim_text(true);
 
-    let mut primary = String::new();
-    let mut filelists = String::new();
+    let mut md = RepoMd::default();
     let mut datatype = 0;
     let mut buf = Vec::new();
 
@@ -150,15 +190,22 @@ fn repomd(url: String) -> Result<(String,String),Box<Error>> {
                         datatype = match &xml_getattr(e, "type")? as &str {
                             "primary"   => 1,
                             "filelists" => 2,
+                            "susedata"  => 3,
                             _           => 0,
                         },
 
-                    b"location" =>
+                    b"location" => {
+                        let href = xml_getattr(e, "href")?;
+                        if href.ends_with(".zck") {
+                            continue;
+                        }
                         match datatype {
-                            1 => primary   = xml_getattr(e, "href")?,
-                            2 => filelists = xml_getattr(e, "href")?,
+                            1 => md.primary   = href,
+                            2 => md.filelists = href,
+                            3 => md.susedata  = Some(href),
                             _ => (),
-                        },
+                        }
+                    },
 
                     _ => (),
                 }
@@ -167,31 +214,50 @@ fn repomd(url: String) -> Result<(String,String),Box<Error>> {
             _ => (),
         }
     }
-    Ok((primary, filelists))
+    Ok(md)
 }
 
 
 pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, mirror: &str) -> Result<(),Box<Error>> {
-    let(primary, filelists) = repomd(format!("{}repodata/repomd.xml", mirror))?;
+    let md = repomd(format!("{}repodata/repomd.xml", mirror))?;
 
     let mut pkgswithman = HashSet::new();
-    readpkgs(format!("{}{}", mirror, filelists), |pkg| {
+    readpkgs(format!("{}{}", mirror, md.filelists), |pkg| {
         if pkg.hasman { pkgswithman.insert(pkg.name.unwrap()); () }
     })?;
 
-    readpkgs(format!("{}{}", mirror, primary), |pkg| {
+    // SUSE repositories have additional package keywords in susedata.xml, use the first keyword
+    // that isn't a support level as category for the package if there is one. Falls back to the
+    // given category otherwise.
+    let mut pkgcats = HashMap::new();
+    if let Some(susedata) = md.susedata {
+        readpkgs(format!("{}{}", mirror, susedata), |pkg| {
+            if let (Some(name), Some(ver), Some(arch), Some(kw)) = (pkg.name, pkg.ver, pkg.arch, pkg.keyword) {
+                if pkgswithman.contains(&name) {
+                    pkgcats.insert((name, ver, arch), kw);
+                }
+            }
+        })?;
+    }
+
+    readpkgs(format!("{}{}", mirror, md.primary), |pkg| {
         let name = pkg.name.unwrap();
         if pkgswithman.contains(&name) {
             let uri = format!("{}{}", mirror, pkg.path.unwrap());
             let date = NaiveDateTime::from_timestamp(pkg.date.unwrap(), 0).format("%Y-%m-%d").to_string();
+            let ver = pkg.ver.unwrap();
+            let arch = pkg.arch.unwrap();
+            let sum = pkg.checksum.as_ref().and_then(|&(ref t, ref h)| pkg::Checksum::new(t, h));
+            let key = (name, ver, arch);
             pkg::pkg(pg, pkg::PkgOpt{
                 force: false,
                 sys: sys,
-                cat: cat,
-                pkg: &name,
-                ver: &pkg.ver.unwrap(),
+                cat: pkgcats.get(&key).map(|e| &e[..]).unwrap_or(cat),
+                pkg: &key.0,
+                ver: &key.1,
                 date: pkg::Date::Known(&date),
-                arch: Some(&pkg.arch.unwrap()),
+                arch: Some(&key.2),
+                digest: sum,
                 file: open::Path{
                     path: &uri,
                     cache: false,
@@ -202,3 +268,31 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, mirror: &str)
     })?;
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_susedata() {
+        let mut f = File::open("tests/susedata.xml.gz").unwrap();
+        let mut pkgs = Vec::new();
+        readpkgs_rd(&mut f, |p| pkgs.push((p.name.unwrap(), p.ver.unwrap(), p.keyword))).unwrap();
+        assert_eq!(pkgs, vec![
+            ("ncdu".to_string(), "1.18-1.1".to_string(), None),
+            ("bash".to_string(), "5.2.15-2.1".to_string(), Some("System/Shells".to_string())),
+            ("vim".to_string(), "9.0.1572-1.1".to_string(), None),
+        ]);
+    }
+
+    #[test]
+    fn test_repomd() {
+        let mut f = File::open("tests/repomd.xml").unwrap();
+        let md = repomd_rd(&mut f).unwrap();
+        assert_eq!(md.primary, "repodata/1a2b-primary.xml.gz");
+        assert_eq!(md.filelists, "repodata/3c4d-filelists.xml.zst");
+        assert_eq!(md.susedata, Some("repodata/5e6f-susedata.xml.gz".to_string()));
+    }
+}
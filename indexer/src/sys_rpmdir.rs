@@ -34,6 +34,7 @@ pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, mirror: &str)
             ver: ver,
             date: pkg::Date::Max,
             arch: Some(arch),
+            digest: None,
             file: open::Path{
                 path: &path,
                 cache: false,
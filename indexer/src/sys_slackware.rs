@@ -0,0 +1,168 @@
+use std::io::{Result,BufReader,BufRead};
+use std::collections::HashSet;
+use std::str;
+use regex::Regex;
+use postgres;
+
+use man;
+use pkg;
+use open;
+use archive;
+
+// Both PACKAGES.TXT and MANIFEST.bz2 are read from the package tree directory (e.g.
+// slackware64-14.2/slackware64/), all locations in these files are relative to that directory.
+//
+// Note that Slackware packages don't contain symlinks, these are created by the install/doinst.sh
+// script instead. Links to man pages are therefore not indexed.
+
+
+fn get_manifest(f: open::Path) -> Result<HashSet<String>> {
+    let mut fd = f.open()?;
+    let rd = archive::Archive::open_raw(&mut fd)?;
+    let (filecnt, pkgs) = read_manifest(BufReader::new(rd))?;
+    info!("Found {} files and {} relevant packages from {}", filecnt, pkgs.len(), f.path);
+    Ok(pkgs)
+}
+
+
+// Returns the number of files and the set of packages ("<series>/<filename>") that have a man page.
+fn read_manifest<R: BufRead>(brd: R) -> Result<(usize, HashSet<String>)> {
+    lazy_static!(
+        static ref PKG: Regex = Regex::new(r"^\|\|\s+Package:\s+\./(.+?)\s*$").unwrap();
+        // tar -tvf output: "<perms> <owner> <size> <date> <time> <path>"
+        static ref FILE: Regex = Regex::new(r"^\S+\s+\S+\s+[0-9]+\s+\S+\s+\S+\s+(.+)$").unwrap();
+    );
+
+    let mut pkgs = HashSet::new();
+    let mut cur = None;
+    let mut filecnt = 0;
+
+    for line in brd.split(b'\n') {
+        let line = line?;
+        let line = match str::from_utf8(&line) { Ok(x) => x, _ => continue };
+
+        if let Some(cap) = PKG.captures(line) {
+            cur = Some(cap[1].to_string());
+            continue;
+        }
+
+        let cap = match FILE.captures(line) { Some(x) => x, None => continue };
+        filecnt += 1;
+        if man::ismanpath(&cap[1]) {
+            if let Some(p) = cur.take() {
+                pkgs.insert(p);
+            }
+        }
+    }
+
+    Ok((filecnt, pkgs))
+}
+
+
+#[derive(Default,Debug,PartialEq)]
+struct Pkg {
+    filename: Option<String>,
+    location: Option<String>,
+}
+
+
+fn handlepkg(pg: &postgres::GenericConnection, sys: i32, mirror: &str, manpkgs: &HashSet<String>, pkg: &Pkg) {
+    lazy_static!(
+        // <name>-<version>-<arch>-<build>.t?z
+        static ref RE: Regex = Regex::new(r"^(.+)-([^-]+)-([^-]+)-([^-]+)\.t[bglx]z$").unwrap();
+    );
+
+    let filename = match pkg.filename { Some(ref x) => x, None => return };
+    let location = match pkg.location { Some(ref x) => x.trim_left_matches("./"), None => { error!("Package {} has no location", filename); return } };
+    let path = format!("{}/{}", location, filename);
+    if !manpkgs.contains(&path) {
+        return
+    }
+
+    let cap = match RE.captures(filename) {
+        Some(x) => x,
+        None => { warn!("Unknown package file: {}", path); return },
+    };
+    // The build number is included in the version, a package may be rebuilt without a version
+    // change.
+    let ver = format!("{}-{}", &cap[2], &cap[4]);
+    let uri = format!("{}{}", mirror, path);
+
+    pkg::pkg(pg, pkg::PkgOpt{
+        force: false,
+        sys: sys,
+        cat: location,
+        pkg: &cap[1],
+        ver: &ver,
+        date: pkg::Date::Max,
+        arch: Some(&cap[3]),
+        digest: None,
+        file: open::Path{
+            path: &uri,
+            cache: false,
+            canbelocal: false,
+        },
+    });
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, mirror: &str) -> Result<()> {
+    let manifest = format!("{}MANIFEST.bz2", mirror);
+    let manpkgs = get_manifest(open::Path{path: &manifest, cache: true, canbelocal: false})?;
+
+    let packages = format!("{}PACKAGES.TXT", mirror);
+    let fd = open::Path{path: &packages, cache: true, canbelocal: false}.open()?;
+    read_packages(BufReader::new(fd), |pkg| handlepkg(pg, sys, mirror, &manpkgs, &pkg))
+}
+
+
+fn read_packages<R: BufRead, F: FnMut(Pkg)>(brd: R, mut cb: F) -> Result<()> {
+    let mut pkg = Pkg::default();
+    for line in brd.split(b'\n') {
+        let line = line?;
+        let line = match str::from_utf8(&line) { Ok(x) => x, _ => continue };
+        if line.starts_with("PACKAGE NAME:") {
+            if pkg.filename.is_some() {
+                cb(pkg);
+            }
+            pkg = Pkg::default();
+            pkg.filename = Some(line["PACKAGE NAME:".len()..].trim().to_string());
+        } else if line.starts_with("PACKAGE LOCATION:") {
+            pkg.location = Some(line["PACKAGE LOCATION:".len()..].trim().to_string());
+        }
+    }
+    if pkg.filename.is_some() {
+        cb(pkg);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_read_manifest() {
+        let f = File::open("tests/MANIFEST").unwrap();
+        let (filecnt, pkgs) = read_manifest(BufReader::new(f)).unwrap();
+        assert_eq!(filecnt, 7);
+        let mut pkgs: Vec<_> = pkgs.into_iter().collect();
+        pkgs.sort();
+        assert_eq!(pkgs, vec!["ap/ncdu-1.13-x86_64-1.txz", "n/openssh-7.7p1-x86_64-1.txz"]);
+    }
+
+    #[test]
+    fn test_read_packages() {
+        let f = File::open("tests/PACKAGES.TXT").unwrap();
+        let mut pkgs = Vec::new();
+        read_packages(BufReader::new(f), |p| pkgs.push(p)).unwrap();
+        let s = |x: &str| Some(x.to_string());
+        assert_eq!(pkgs, vec![
+            Pkg { filename: s("ncdu-1.13-x86_64-1.txz"), location: s("./ap") },
+            Pkg { filename: s("openssh-7.7p1-x86_64-1.txz"), location: s("./n") },
+            Pkg { filename: s("zlib-1.2.11-x86_64-2.txz"), location: None },
+        ]);
+    }
+}
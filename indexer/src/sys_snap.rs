@@ -0,0 +1,158 @@
+use std::io::{Read,Result,Error,ErrorKind};
+use std::fs::read_dir;
+use postgres;
+
+use archive::{walk,Entry};
+use archread::FileList;
+use squashfs::SquashEntry;
+use open;
+use pkg;
+use man;
+
+
+#[derive(Debug,PartialEq,Eq,Default)]
+struct Meta {
+    name: Option<String>,
+    version: Option<String>,
+    arch: Option<String>,
+}
+
+
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    if s.len() >= 2 && (s.starts_with('\'') && s.ends_with('\'') || s.starts_with('"') && s.ends_with('"')) {
+        &s[1..s.len()-1]
+    } else {
+        s
+    }
+}
+
+
+// Extract the name, version and first architecture from a meta/snap.yaml. This isn't a YAML
+// parser, it only looks at top-level "key: value" lines and the first item of the architectures
+// list, which may be either a "- <arch>" sequence or an inline "[<arch>, ..]".
+fn parse_meta(data: &str) -> Meta {
+    let mut meta = Meta::default();
+    let mut inarch = false;
+    for line in data.lines() {
+        if inarch {
+            match line.trim().splitn(2, "- ").nth(1) {
+                Some(a) if line.trim().starts_with("- ") => {
+                    meta.arch = Some(unquote(a).to_string());
+                    inarch = false;
+                    continue;
+                },
+                _ if line.starts_with(' ') || line.trim() == "" => continue,
+                _ => inarch = false,
+            }
+        }
+        if line.starts_with(' ') || line.starts_with('#') {
+            continue;
+        }
+        let mut kv = line.splitn(2, ':');
+        let (key, val) = match (kv.next(), kv.next()) { (Some(k), Some(v)) => (k.trim(), unquote(v)), _ => continue };
+        match key {
+            "name" => meta.name = Some(val.to_string()),
+            "version" => meta.version = Some(val.to_string()),
+            "architectures" if val == "" => inarch = true,
+            "architectures" => meta.arch = val.trim_left_matches('[').trim_right_matches(']')
+                .split(',').next().map(|a| unquote(a).to_string()).filter(|a| a != ""),
+            _ => (),
+        }
+    }
+    meta
+}
+
+
+fn read_meta(path: &str) -> Result<Meta> {
+    let mut meta = None;
+    walk(SquashEntry::open(path)?, |e| {
+        if e.path() == Some("meta/snap.yaml") {
+            let mut data = String::new();
+            e.take(1024*1024).read_to_string(&mut data)?;
+            meta = Some(parse_meta(&data));
+        }
+        Ok(meta.is_none())
+    })?;
+    meta.ok_or_else(|| Error::new(ErrorKind::Other, "No meta/snap.yaml found"))
+}
+
+
+fn index(pg: &postgres::GenericConnection, sys: i32, cat: &str, path: &str) -> Result<()> {
+    let meta = read_meta(path)?;
+    let (name, ver) = match (meta.name, meta.version) {
+        (Some(n), Some(v)) => (n, v),
+        _ => return Err(Error::new(ErrorKind::Other, "No name or version in meta/snap.yaml")),
+    };
+
+    pkg::pkg_with(pg, pkg::PkgOpt{
+        force: false,
+        sys: sys,
+        cat: cat,
+        pkg: &name,
+        ver: &ver,
+        date: pkg::Date::Max,
+        arch: meta.arch.as_ref().map(|e| &e[..]),
+        digest: None,
+        file: open::Path{
+            path: path,
+            cache: false,
+            canbelocal: true,
+        },
+    }, |tr, opt, verid, stubs| {
+        let indexfunc = |paths: &[&str], ent: &mut SquashEntry| {
+            pkg::insert_man(tr, verid, stubs, paths, ent);
+            Ok(())
+        };
+
+        let missed = FileList::read(SquashEntry::open(path)?, man::ismanpath, |ent| opt.date.update(ent), &indexfunc, |path| pkg::delete_man(tr, verid, stubs, path))?
+            .links(|src, dest| { pkg::insert_link(tr, verid, stubs, src, dest) });
+
+        if let Some(missed) = missed {
+            warn!("Some links were missed, reading snap again");
+            missed.read(SquashEntry::open(path)?, indexfunc)?
+        }
+        Ok(())
+    });
+    Ok(())
+}
+
+
+// Index all .snap files in a local directory. The package name, version and architecture are read
+// from the meta/snap.yaml inside the snap.
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, dir: &str) -> Result<()> {
+    let mut files = Vec::new();
+    for f in read_dir(dir)? {
+        let f = f?.path();
+        if f.extension().map(|e| e == "snap").unwrap_or(false) {
+            files.push(f);
+        }
+    }
+    files.sort();
+
+    for f in files {
+        let path = match f.to_str() { Some(x) => x, None => { warn!("Invalid UTF-8 filename: {:?}", f); continue } };
+        if let Err(e) = index(pg, sys, cat, path) {
+            error!("Error reading {}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+
+#[test]
+fn test_parse_meta() {
+    let m = |n: &str, v: &str, a: Option<&str>| Meta {
+        name: Some(n.to_string()),
+        version: Some(v.to_string()),
+        arch: a.map(str::to_string),
+    };
+    assert_eq!(parse_meta("name: hello\nversion: '2.10'\nsummary: GNU Hello\narchitectures:\n  - amd64\n  - i386\n"),
+        m("hello", "2.10", Some("amd64")));
+    assert_eq!(parse_meta("name: core\nversion: \"16-2.35\"\narchitectures: [armhf, arm64]\ntype: os\n"),
+        m("core", "16-2.35", Some("armhf")));
+    assert_eq!(parse_meta("name: x\narchitectures:\n- all\nversion: 1\n"),
+        m("x", "1", Some("all")));
+    assert_eq!(parse_meta("# comment\nname: y\nversion: 0.1\napps:\n  y:\n    command: bin/y\n"),
+        m("y", "0.1", None));
+}
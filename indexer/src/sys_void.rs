@@ -0,0 +1,206 @@
+use std::io::{Read,BufReader};
+use std::error::Error;
+use postgres;
+use regex::Regex;
+use quick_xml as xml;
+use quick_xml::events::Event;
+
+use archive::{Archive,ArchiveEntry};
+use sys_rpm::{XmlError,to_err};
+use open;
+use pkg;
+
+
+#[derive(Debug,PartialEq,Eq)]
+struct Meta {
+    name: String,
+    version: String,
+    arch: Option<String>,
+    date: Option<String>,
+}
+
+
+fn getindex(mut ent: Option<ArchiveEntry>) -> Result<ArchiveEntry,Box<Error>> {
+    while let Some(e) = ent {
+        if e.path() == Some("index.plist") {
+            return Ok(e)
+        }
+        ent = e.next()?
+    }
+    Err(Box::new(XmlError("No index.plist found".to_string())))
+}
+
+
+// <name>-<version>_<revision>; Version and revision can't contain a dash.
+fn splitpkgver(pkgver: &str) -> Option<(&str, &str)> {
+    match pkgver.rfind('-') {
+        Some(i) if i > 0 && i < pkgver.len()-1 => Some((&pkgver[..i], &pkgver[i+1..])),
+        _ => None,
+    }
+}
+
+
+// The build-date is formatted as "%F %R %Z" (e.g. "2018-02-04 11:35 CET"), only the date part is
+// of interest.
+fn parsedate(date: &str) -> Option<String> {
+    lazy_static!(
+        static ref RE: Regex = Regex::new(r"^([0-9]{4}-[0-9]{2}-[0-9]{2})\b").unwrap();
+    );
+    RE.captures(date).map(|c| c[1].to_string())
+}
+
+
+// Reads the index.plist from a repodata archive and calls the callback for each package.
+//
+// The plist is a <dict> of package name -> <dict> with package metadata. Only top-level <string>
+// values in the package dict are of interest, the rest is skipped.
+fn readindex<F>(rd: &mut Read, mut cb: F) -> Result<(),Box<Error>>
+    where F: FnMut(Meta)
+{
+    let ent = Archive::open_archive(rd)?;
+    let mut xml = xml::Reader::from_reader(BufReader::new(getindex(ent)?));
+    xml.trim_text(true);
+
+    let mut depth = 0;
+    let mut savestr = false;
+    let mut saved = None;
+    let mut key = String::new();
+    // Key of the package in the outer dictionary
+    let mut pkgkey = String::new();
+    let mut pkgver = None;
+    let mut arch = None;
+    let mut date = None;
+    let mut buf = Vec::new();
+
+    loop {
+        {
+            let event = xml.read_event(&mut buf).map_err(to_err)?;
+            match event {
+                Event::Start(ref e) =>
+                    match e.name() {
+                        b"dict" | b"array" => depth += 1,
+                        b"key" if depth == 1 => savestr = true,
+                        b"key" | b"string" if depth == 2 => savestr = true,
+                        _ => (),
+                    },
+
+                Event::Text(e) =>
+                    if savestr {
+                        saved = Some(e.unescape_and_decode(&xml).map_err(to_err)?);
+                        savestr = false
+                    },
+
+                Event::End(ref e) => {
+                    savestr = false;
+                    match e.name() {
+                        b"key" if depth == 1 => pkgkey = saved.take().unwrap_or_default(),
+                        b"key" if depth == 2 => key = saved.take().unwrap_or_default(),
+                        b"string" if depth == 2 => match &key as &str {
+                            "pkgver"       => pkgver = saved.take(),
+                            "architecture" => arch   = saved.take(),
+                            "build-date"   => date   = saved.take(),
+                            _ => (),
+                        },
+                        b"dict" if depth == 2 => {
+                            match pkgver.take().as_ref().and_then(|p| splitpkgver(p)) {
+                                Some((name, ver)) => cb(Meta {
+                                    name: name.to_string(),
+                                    version: ver.to_string(),
+                                    arch: arch.take(),
+                                    date: date.take().and_then(|d| parsedate(&d)),
+                                }),
+                                None => warn!("Package without valid pkgver in index.plist: {}", pkgkey),
+                            }
+                            arch = None;
+                            date = None;
+                            depth -= 1;
+                        },
+                        b"dict" | b"array" => depth -= 1,
+                        _ => (),
+                    };
+                },
+
+                Event::Eof => break,
+                _ => (),
+            }
+        }
+        buf.clear();
+    }
+    Ok(())
+}
+
+
+pub fn sync(pg: &postgres::GenericConnection, sys: i32, cat: &str, mirror: &str, arch: &str) -> Result<(),Box<Error>> {
+    info!("Reading packages from {} {}", mirror, arch);
+
+    let path = format!("{}/{}-repodata", mirror, arch);
+    let mut rd = open::Path{path: &path, cache: true, canbelocal: false}.open()?;
+
+    // xbps repositories don't have a file list, so every package has to be fetched.
+    readindex(&mut rd, |m| {
+        let date = match m.date {
+            Some(ref x) => x,
+            None => { error!("Package {} has no valid build-date", m.name); return }
+        };
+        let parch = m.arch.as_ref().map(|e| &e[..]).unwrap_or(arch);
+        let p = format!("{}/{}-{}.{}.xbps", mirror, m.name, m.version, parch);
+        pkg::pkg(pg, pkg::PkgOpt{
+            force: false,
+            sys: sys,
+            cat: cat,
+            pkg: &m.name,
+            ver: &m.version,
+            date: pkg::Date::Known(date),
+            arch: Some(parch),
+            digest: None,
+            file: open::Path{
+                path: &p,
+                cache: false,
+                canbelocal: false,
+            },
+        });
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_readindex() {
+        let mut f = File::open("tests/x86_64-repodata").unwrap();
+        let mut pkgs = Vec::new();
+        readindex(&mut f, |m| pkgs.push(m)).unwrap();
+
+        assert_eq!(pkgs, vec![
+            Meta {
+                name: "ncdu".to_string(),
+                version: "1.13_1".to_string(),
+                arch: Some("x86_64".to_string()),
+                date: Some("2018-02-04".to_string()),
+            },
+            Meta {
+                name: "man-pages-posix".to_string(),
+                version: "2013a_2".to_string(),
+                arch: Some("noarch".to_string()),
+                date: Some("2017-12-10".to_string()),
+            },
+            Meta {
+                name: "broken".to_string(),
+                version: "1.0_1".to_string(),
+                arch: None,
+                date: None,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_splitpkgver() {
+        assert_eq!(splitpkgver("ncdu-1.13_1"), Some(("ncdu", "1.13_1")));
+        assert_eq!(splitpkgver("man-pages-posix-2013a_2"), Some(("man-pages-posix", "2013a_2")));
+        assert_eq!(splitpkgver("ncdu"), None);
+        assert_eq!(splitpkgver("ncdu-"), None);
+    }
+}
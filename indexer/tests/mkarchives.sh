@@ -25,6 +25,11 @@ rm -rf $badfn simple
 echo "File contents!" | gzip | xz | bzip2 >rawtest.gz.xz.bzip2
 
 
+# rawtest.zst (Used for e.g. openSUSE repodata)
+
+echo "File contents!" | zstd -q >rawtest.zst
+
+
 # testarchive.tar.xz
 
 mkdir man
@@ -73,3 +78,308 @@ rm -r man/
 
 rm -f testarchive.tar.xz
 xz testarchive.tar
+
+
+# duplicates.tar.xz
+
+mkdir -p man/man1
+echo 'First version' >man/man1/dup.1
+echo 'Replaced by a link' >man/man1/tolink.1
+ln -s dup.1 man/man1/fromlink.1
+echo 'Old target' >man/target
+ln -s ../target man/man1/needreread.1
+
+rm -f duplicates.tar
+tar -cf duplicates.tar man/
+rm -r man/
+
+mkdir -p man/man1
+echo 'Second version' >man/man1/dup.1
+ln -s dup.1 man/man1/tolink.1
+echo 'No longer a link' >man/man1/fromlink.1
+echo 'New target' >man/target
+tar -rf duplicates.tar man/
+rm -r man/
+
+rm -f duplicates.tar.xz
+xz duplicates.tar
+
+
+
+# nonutf8.tar.xz
+
+mkdir -p man/ja_JP.eucJP/man1 man/ru/man1
+ja=`printf 'テスト' | iconv -f UTF-8 -t EUC-JP`
+ru=`printf 'тест' | iconv -f UTF-8 -t KOI8-R`
+echo 'EUC-JP file name' >man/ja_JP.eucJP/man1/$ja.1
+ln -s $ja.1 man/ja_JP.eucJP/man1/link.1
+echo 'KOI8-R file name' >man/ru/man1/$ru.1
+ln man/ru/man1/$ru.1 man/ru/man1/hardlink.1
+rm -f nonutf8.tar.xz
+# Explicit order, so that the hardlink comes after the file
+tar -cJf nonutf8.tar.xz man/ja_JP.eucJP/man1/$ja.1 man/ja_JP.eucJP/man1/link.1 man/ru/man1/$ru.1 man/ru/man1/hardlink.1
+rm -r man/
+
+
+
+# squashfs.squashfs, squashfs-gzip.squashfs
+
+mkdir -p sq/usr/share/man/man1 sq/usr/share/man/man8 sq/usr/share/empty
+echo '.TH SMALL 1' >sq/usr/share/man/man1/small.1
+seq 1 2000 | sed 's/^/.\\" line /' | head -c 10000 >sq/usr/share/man/man1/large.1
+yes '.\" padding' | head -c 8192 >sq/usr/share/man/man1/exact.1
+ln -s small.1 sq/usr/share/man/man1/link.1
+ln -s /usr/share/man/man1/large.1 sq/usr/share/man/man8/abs.8
+mkdir -p sq/usr/share/man/ru/man1
+ru=`printf 'тест' | iconv -f UTF-8 -t KOI8-R`
+echo 'KOI8-R file name' >sq/usr/share/man/ru/man1/$ru.1
+ln -s $ru.1 sq/usr/share/man/ru/man1/link.1
+rm -f squashfs.squashfs squashfs-gzip.squashfs
+# A small block size, so that large.1 spans two blocks plus a fragment and
+# exact.1 two blocks without fragment.
+mksquashfs sq squashfs.squashfs -comp xz -b 4096 -all-root -no-xattrs -no-progress >/dev/null
+mksquashfs sq squashfs-gzip.squashfs -comp gzip -b 4096 -all-root -no-xattrs -no-progress >/dev/null
+rm -r sq/
+
+
+
+# x86_64-repodata (Void Linux)
+
+cat >index.plist <<'PLIST'
+<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple Computer//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>ncdu</key>
+	<dict>
+		<key>architecture</key>
+		<string>x86_64</string>
+		<key>build-date</key>
+		<string>2018-02-04 11:35 CET</string>
+		<key>filename-size</key>
+		<integer>41234</integer>
+		<key>pkgver</key>
+		<string>ncdu-1.13_1</string>
+		<key>run_depends</key>
+		<array>
+			<string>glibc&gt;=2.26_1</string>
+			<string>ncurses-libs&gt;=5.8_1</string>
+		</array>
+		<key>short_desc</key>
+		<string>NCurses Disk Usage utility</string>
+	</dict>
+	<key>man-pages-posix</key>
+	<dict>
+		<key>alternatives</key>
+		<dict>
+			<key>pkgver</key>
+			<array>
+				<string>not-a-pkgver-1.0_1</string>
+			</array>
+		</dict>
+		<key>architecture</key>
+		<string>noarch</string>
+		<key>build-date</key>
+		<string>2017-12-10 20:01 UTC</string>
+		<key>pkgver</key>
+		<string>man-pages-posix-2013a_2</string>
+		<key>preserve</key>
+		<true/>
+	</dict>
+	<key>broken</key>
+	<dict>
+		<key>pkgver</key>
+		<string>broken-1.0_1</string>
+	</dict>
+</dict>
+</plist>
+PLIST
+tar -cJf x86_64-repodata index.plist
+rm index.plist
+
+
+
+# susedata.xml.gz (openSUSE rpm-md)
+
+cat >susedata.xml <<'XML'
+<?xml version="1.0" encoding="UTF-8"?>
+<susedata xmlns="http://linux.duke.edu/metadata/susedata" packages="3">
+<package pkgid="5d1d5ea6b4dd2d4c8f7d12e8f6d0d0b3f8b6f2c5" name="ncdu" arch="x86_64">
+  <version epoch="0" ver="1.18" rel="1.1"/>
+  <keyword>support_unsupported</keyword>
+</package>
+<package pkgid="0b7cf2a2b1e8e9ae9dbdb3ee7f0ac1c4c7a4a4c1" name="bash" arch="x86_64">
+  <version epoch="0" ver="5.2.15" rel="2.1"/>
+  <keyword>support_l3</keyword>
+  <keyword>System/Shells</keyword>
+</package>
+<package pkgid="c5f5aeb8d7e6f9c4b3a2918273645546372819a0" name="vim" arch="x86_64">
+  <version epoch="0" ver="9.0.1572" rel="1.1"/>
+</package>
+</susedata>
+XML
+gzip -n susedata.xml
+
+
+
+# PACKAGES.TXT and MANIFEST (Slackware, MANIFEST is normally bzip2-compressed)
+
+cat >PACKAGES.TXT <<'EOF'
+PACKAGES.TXT;  Sun Jun  3 12:00:00 UTC 2018
+
+This file provides details on the Slackware packages found
+in this directory.
+
+Total size of all packages (compressed):  3 MB
+Total size of all packages (uncompressed):  9 MB
+
+
+PACKAGE NAME:  ncdu-1.13-x86_64-1.txz
+PACKAGE LOCATION:  ./ap
+PACKAGE SIZE (compressed):  48 K
+PACKAGE SIZE (uncompressed):  120 K
+PACKAGE DESCRIPTION:
+ncdu: ncdu (NCurses Disk Usage)
+
+PACKAGE NAME:  openssh-7.7p1-x86_64-1.txz
+PACKAGE LOCATION:  ./n
+PACKAGE SIZE (compressed):  1040 K
+PACKAGE SIZE (uncompressed):  4630 K
+PACKAGE DESCRIPTION:
+openssh: openssh (Secure Shell daemon and clients)
+
+PACKAGE NAME:  zlib-1.2.11-x86_64-2.txz
+PACKAGE SIZE (compressed):  88 K
+PACKAGE DESCRIPTION:
+zlib: zlib (compression library)
+
+EOF
+
+cat >MANIFEST <<'EOF'
+++========================================
+||
+||   Package:  ./ap/ncdu-1.13-x86_64-1.txz
+||
+++========================================
+drwxr-xr-x root/root         0 2018-02-04 12:00 ./
+-rwxr-xr-x root/root     73856 2018-02-04 12:00 usr/bin/ncdu
+-rw-r--r-- root/root      6036 2018-02-04 12:00 usr/man/man1/ncdu.1.gz
+
+
+++========================================
+||
+||   Package:  ./n/openssh-7.7p1-x86_64-1.txz
+||
+++========================================
+drwxr-xr-x root/root         0 2018-04-02 08:00 ./
+-rw-r--r-- root/root     10124 2018-04-02 08:00 usr/man/man1/ssh.1.gz
+-rw-r--r-- root/root      9160 2018-04-02 08:00 usr/man/man8/sshd.8.gz
+
+
+++========================================
+||
+||   Package:  ./l/zlib-1.2.11-x86_64-2.txz
+||
+++========================================
+-rwxr-xr-x root/root    104776 2017-01-16 10:00 usr/lib64/libz.so.1.2.11
+EOF
+
+
+
+# freebsd-MANIFEST (FreeBSD release dist sets, tab-separated)
+
+printf '%s\t%s\t%s\t%s\t%s\t%s\n' \
+  base.txz 8ab8aeb0c3a8fe13d2f0b1fdeb62ae2bc2a4cd4c9fd0e4a4e9b2ae16b5e7b7c1 26126 base '"Base system (MANDATORY)"' on \
+  kernel.txz 1c0b5f7b8a9de0c6e0c6a6c9b6e5d7e6e06fc14b3a62c18b3ce1e6d0d23f6f1a 818 kernel '"Kernel (MANDATORY)"' on \
+  lib32.txz '' 762 lib32 '"32-bit compatibility libraries"' on \
+  src.txz 0d2ec1c1b0e2a3e3f53f2e0d7e1b6fd8f6e4d6c1d9b3e1a0e9c6f2c5b3a1d0e9 86003 src '"System source tree"' off \
+  >freebsd-MANIFEST
+
+
+
+# APKINDEX (Alpine Linux, the uncompressed index from APKINDEX.tar.gz)
+
+cat >APKINDEX <<'EOF'
+C:Q1mB0Uq8Bz2FqHzDP5zrQK2y1XxVs=
+P:ncdu-doc
+V:1.13-r0
+A:noarch
+S:4321
+I:20480
+T:Ncurses Disk Usage (documentation)
+t:1517702400
+
+C:Q1kYh0pVZx7w3tRXGmUXxKpMVeQ4w=
+P:ncdu
+V:1.13-r0
+A:x86_64
+S:12345
+I:40960
+T:Ncurses Disk Usage
+t:1517702400
+
+C:Q1ZrcXv0yWq6dD1vqUSR3nA3m0xQI=
+P:broken-doc
+V:1.0-r0
+A:noarch
+t:9999999999999999
+
+EOF
+
+
+
+# repomd.xml (rpm-md, with zchunk variants that should be ignored)
+
+cat >repomd.xml <<'XML'
+<?xml version="1.0" encoding="UTF-8"?>
+<repomd xmlns="http://linux.duke.edu/metadata/repo" xmlns:rpm="http://linux.duke.edu/metadata/rpm">
+  <revision>1690000000</revision>
+  <data type="primary">
+    <location href="repodata/1a2b-primary.xml.gz"/>
+  </data>
+  <data type="primary_zck">
+    <location href="repodata/1a2b-primary.xml.zck"/>
+  </data>
+  <data type="filelists">
+    <location href="repodata/3c4d-filelists.xml.zst"/>
+  </data>
+  <data type="filelists">
+    <location href="repodata/3c4d-filelists.xml.zck"/>
+  </data>
+  <data type="susedata">
+    <location href="repodata/5e6f-susedata.xml.gz"/>
+  </data>
+  <data type="susedata_zck">
+    <location href="repodata/5e6f-susedata.xml.zck"/>
+  </data>
+</repomd>
+XML
+
+
+
+# ociimage.tar ("docker save" format)
+#
+# Layer 1 has some man pages, layer 2 overwrites one, removes another with a
+# whiteout file and replaces the man5 directory with an opaque whiteout.
+
+mkdir -p oci/l1/usr/share/man/man1 oci/l1/usr/share/man/man5 oci/l2/usr/share/man/man1 oci/l2/usr/share/man/man5
+echo 'Foo man page' >oci/l1/usr/share/man/man1/foo.1
+echo 'Old bar man page' >oci/l1/usr/share/man/man1/bar.1
+echo 'Removed man page' >oci/l1/usr/share/man/man1/gone.1
+echo 'Hidden man page' >oci/l1/usr/share/man/man5/hidden.5
+ln -s bar.1 oci/l1/usr/share/man/man1/barlink.1
+echo 'New bar man page' >oci/l2/usr/share/man/man1/bar.1
+touch oci/l2/usr/share/man/man1/.wh.gone.1
+touch oci/l2/usr/share/man/man5/.wh..wh..opq
+echo 'Visible man page' >oci/l2/usr/share/man/man5/visible.5
+
+cd oci
+mkdir layer1 layer2
+tar -cf layer1/layer.tar -C l1 usr
+tar -cf layer2/layer.tar -C l2 usr
+rm -rf l1 l2
+echo '{"architecture":"amd64","created":"2018-04-20T16:20:00.123456789Z","os":"linux"}' >config.json
+echo '[{"Config":"config.json","RepoTags":["manned/test:1.0","manned/test:latest"],"Layers":["layer1/layer.tar","layer2/layer.tar"]}]' >manifest.json
+tar -cf ../ociimage.tar manifest.json config.json layer1 layer2
+cd ..
+rm -rf oci
@@ -3,11 +3,12 @@
 . ./common.sh
 
 MIRROR=http://ftp.nluug.nl/pub/os/Linux/distr/archlinux
+MIRROR2=https://mirrors.kernel.org/archlinux
 
 case "$1" in
     current)
-        index arch --sys arch --mirror $MIRROR --repo core
-        index arch --sys arch --mirror $MIRROR --repo extra
-        index arch --sys arch --mirror $MIRROR --repo community
+        index arch --sys arch --mirror $MIRROR --mirror $MIRROR2 --repo core
+        index arch --sys arch --mirror $MIRROR --mirror $MIRROR2 --repo extra
+        index arch --sys arch --mirror $MIRROR --mirror $MIRROR2 --repo community
         ;;
 esac
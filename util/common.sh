@@ -30,3 +30,12 @@ index_deb() {
     index deb --sys "$SYS" --mirror "$MIRROR" --contents "$MIRROR$CONT" --packages "${MIRROR}dists/$DISTRO/$CMP/binary-$ARCH/Packages.gz"
   done
 }
+
+
+# Same as index_deb, but reads the components and files from the (In)Release
+# file. Requires a Release file with SHA256 checksums. Without arch, the
+# architectures listed in the Release file are indexed.
+# Usage: index_deb_suite sys mirror distro [arch]
+index_deb_suite() {
+  index deb --sys "$1" --mirror "$2" --suite "$3" ${4:+--arch "$4"}
+}
@@ -56,8 +56,8 @@ case "$1" in
         index_deb debian-jessie $CMIRROR jessie-updates "main contrib non-free" cmp
         ;;
     stretch)
-        index_deb debian-stretch $CMIRROR stretch "main contrib non-free" cmp
-        index_deb debian-stretch $CMIRROR stretch-updates "main contrib non-free" cmp
+        index_deb_suite debian-stretch $CMIRROR stretch
+        index_deb_suite debian-stretch $CMIRROR stretch-updates
         ;;
     old)
         $0 buzz